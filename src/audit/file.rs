@@ -0,0 +1,100 @@
+use std::path::{Path, PathBuf};
+
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+use crate::error::Error;
+
+/// Append only file that is rotated once it grows past `max_size` bytes, keeping
+/// `max_backups` rotated files named `<path>.1` (newest) to `<path>.<max_backups>`
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_backups: usize,
+}
+
+impl RotatingFile {
+    pub(crate) async fn open(
+        path: PathBuf,
+        max_size: u64,
+        max_backups: usize,
+    ) -> Result<Self, Error> {
+        let file = open_append(&path).await?;
+        let size = file.metadata().await?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            max_backups,
+        })
+    }
+
+    pub(crate) async fn write(&mut self, buf: &[u8]) -> Result<(), Error> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate().await?;
+        }
+        self.file.write_all(buf).await?;
+        self.file.flush().await?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    async fn rotate(&mut self) -> Result<(), Error> {
+        info!("rotating audit log {:?}", self.path);
+        if self.max_backups == 0 {
+            tokio::fs::remove_file(&self.path).await?;
+        } else {
+            for n in (1..self.max_backups).rev() {
+                let from = backup_path(&self.path, n);
+                if tokio::fs::try_exists(&from).await? {
+                    tokio::fs::rename(&from, backup_path(&self.path, n + 1)).await?;
+                }
+            }
+            tokio::fs::rename(&self.path, backup_path(&self.path, 1)).await?;
+        }
+        self.file = open_append(&self.path).await?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+async fn open_append(path: impl AsRef<Path>) -> Result<File, Error> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?)
+}
+
+fn backup_path(path: &Path, n: usize) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(format!(".{n}"));
+    PathBuf::from(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rotates_and_keeps_backups() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("audit.log");
+
+        let mut file = RotatingFile::open(path.clone(), 10, 2).await.unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write(line.as_bytes()).await.unwrap();
+        }
+
+        let read = |p: PathBuf| async move { tokio::fs::read_to_string(p).await.unwrap() };
+        assert_eq!(read(path.clone()).await, "fourth\n");
+        assert_eq!(read(backup_path(&path, 1)).await, "third\n");
+        assert_eq!(read(backup_path(&path, 2)).await, "second\n");
+        assert!(!backup_path(&path, 3).exists());
+    }
+}
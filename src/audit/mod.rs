@@ -0,0 +1,224 @@
+mod file;
+mod webhook;
+
+use aws_smithy_types::date_time::Format;
+use aws_smithy_types::DateTime;
+use serde::Serialize;
+use std::time::SystemTime;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use tracing::{error, info};
+
+use crate::config::{AuditConfig, AuditSinkKind};
+use crate::error::Error;
+use file::RotatingFile;
+use webhook::WebhookSink;
+
+// maximum number of events written to a sink at once
+const BATCH_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AuditEventKind {
+    CredentialRequest,
+    /// A reload of the mappings removed a role from the service accounts of a mapping
+    Revocation,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CacheResult {
+    Hit,
+    Miss,
+    Stale,
+    Negative,
+    /// Cached credentials were dropped
+    Evicted,
+}
+
+/// A single audit record. Tokens and credentials must never be added to it.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEvent {
+    pub timestamp: String,
+    pub kind: AuditEventKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_account: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pod_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pod_uid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role_arn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role_alias: Option<String>,
+    /// Namespaces and service accounts of the mapping a revocation is for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mapping: Option<String>,
+    /// Sessions of the role issued before this time were revoked in IAM
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revoked_before: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheResult>,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(kind: AuditEventKind) -> Self {
+        Self {
+            timestamp: DateTime::from(SystemTime::now())
+                .fmt(Format::DateTime)
+                .unwrap_or_default(),
+            kind,
+            peer_ip: None,
+            namespace: None,
+            service_account: None,
+            pod_name: None,
+            pod_uid: None,
+            role_arn: None,
+            role_alias: None,
+            mapping: None,
+            revoked_before: None,
+            session_name: None,
+            cache: None,
+            outcome: Outcome::Success,
+            error_code: None,
+        }
+    }
+}
+
+/// Handle used to emit audit events. Events are written to the configured sink by a
+/// background task so request handling never waits on the sink.
+#[derive(Clone)]
+pub struct Auditor {
+    tx: Option<Sender<AuditEvent>>,
+}
+
+impl Auditor {
+    pub async fn try_new(cfg: &AuditConfig) -> Result<Self, Error> {
+        let sink = match cfg.audit_sink {
+            AuditSinkKind::None => return Ok(Self { tx: None }),
+            AuditSinkKind::Stdout => Sink::Stdout(tokio::io::stdout()),
+            AuditSinkKind::File => {
+                let path = cfg.audit_file_path.clone().ok_or_else(|| {
+                    Error::AuditError("--audit-file-path is required for the file sink".into())
+                })?;
+                Sink::File(
+                    RotatingFile::open(path, cfg.audit_file_max_size, cfg.audit_file_max_backups)
+                        .await?,
+                )
+            }
+            AuditSinkKind::Webhook => {
+                let url = cfg.audit_webhook_url.as_deref().ok_or_else(|| {
+                    Error::AuditError("--audit-webhook-url is required for the webhook sink".into())
+                })?;
+                Sink::Webhook(Box::new(WebhookSink::try_new(url)?))
+            }
+        };
+        info!("writing audit events to {:?} sink", cfg.audit_sink);
+        let (tx, rx) = channel(cfg.audit_buffer_size);
+        tokio::spawn(write_events(sink, rx));
+        Ok(Self { tx: Some(tx) })
+    }
+
+    /// Auditor sending the events to the returned receiver instead of a sink
+    #[cfg(test)]
+    pub(crate) fn channel() -> (Self, Receiver<AuditEvent>) {
+        let (tx, rx) = channel(BATCH_SIZE);
+        (Self { tx: Some(tx) }, rx)
+    }
+
+    pub fn emit(&self, event: AuditEvent) {
+        let Some(ref tx) = self.tx else {
+            return;
+        };
+        match tx.try_send(event) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                metrics::counter!("audit_events_dropped").increment(1);
+                error!("audit buffer full, dropping event");
+            }
+            Err(TrySendError::Closed(_)) => {
+                metrics::counter!("audit_events_dropped").increment(1);
+                error!("audit writer stopped, dropping event");
+            }
+        }
+    }
+}
+
+enum Sink {
+    Stdout(tokio::io::Stdout),
+    File(RotatingFile),
+    Webhook(Box<WebhookSink>),
+}
+
+impl Sink {
+    async fn write(&mut self, events: &[AuditEvent]) -> Result<(), Error> {
+        match self {
+            Sink::Stdout(stdout) => {
+                stdout.write_all(&to_json_lines(events)?).await?;
+                stdout.flush().await?;
+                Ok(())
+            }
+            Sink::File(file) => file.write(&to_json_lines(events)?).await,
+            Sink::Webhook(webhook) => webhook.send(events).await,
+        }
+    }
+}
+
+fn to_json_lines(events: &[AuditEvent]) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![];
+    for event in events {
+        serde_json::to_writer(&mut buf, event)?;
+        buf.push(b'\n');
+    }
+    Ok(buf)
+}
+
+async fn write_events(mut sink: Sink, mut rx: Receiver<AuditEvent>) {
+    let mut events = Vec::with_capacity(BATCH_SIZE);
+    while rx.recv_many(&mut events, BATCH_SIZE).await > 0 {
+        if let Err(e) = sink.write(&events).await {
+            metrics::counter!("audit_events_failed").increment(events.len() as u64);
+            error!("failed to write {} audit events: {}", events.len(), e);
+        }
+        events.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_serialization() {
+        let mut event = AuditEvent::new(AuditEventKind::CredentialRequest);
+        event.timestamp = "2025-01-01T00:00:00Z".into();
+        event.namespace = Some("default".into());
+        event.service_account = Some("test".into());
+        event.role_arn = Some("arn:aws:iam::123456789000:role/read-only".into());
+        event.cache = Some(CacheResult::Hit);
+        let line = String::from_utf8(to_json_lines(&[event]).unwrap()).unwrap();
+        assert_eq!(
+            line,
+            r#"{"timestamp":"2025-01-01T00:00:00Z","kind":"credentialRequest","namespace":"default","serviceAccount":"test","roleArn":"arn:aws:iam::123456789000:role/read-only","cache":"hit","outcome":"success"}"#
+                .to_owned()
+                + "\n"
+        );
+    }
+}
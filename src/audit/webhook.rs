@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+use axum::body::Bytes;
+use http::{header, Method, Request, Uri};
+use http_body_util::Full;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+
+use super::AuditEvent;
+use crate::error::Error;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts batches of audit events as a JSON array to an HTTP(S) endpoint
+pub(crate) struct WebhookSink {
+    uri: Uri,
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+}
+
+impl WebhookSink {
+    pub(crate) fn try_new(url: &str) -> Result<Self, Error> {
+        let uri: Uri = url
+            .parse()
+            .map_err(|e| Error::AuditError(format!("invalid audit webhook url: {e}")))?;
+        let connector = HttpsConnectorBuilder::new()
+            .with_native_roots()?
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let client = Client::builder(TokioExecutor::new()).build(connector);
+        Ok(Self { uri, client })
+    }
+
+    pub(crate) async fn send(&self, events: &[AuditEvent]) -> Result<(), Error> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(serde_json::to_vec(events)?)))
+            .map_err(|e| Error::AuditError(e.to_string()))?;
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| Error::AuditError("audit webhook request timed out".into()))?
+            .map_err(|e| Error::AuditError(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(Error::AuditError(format!(
+                "audit webhook responded with {}",
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
 // Container credentials expects this network addr over http
 pub const CONTAINER_IPV4_ADDR: Ipv4Addr = Ipv4Addr::new(169, 254, 170, 23);
@@ -15,12 +15,47 @@ pub const CONTAINER_IPV6_ADDR: Ipv6Addr = Ipv6Addr::new(0xfd00, 0xec2, 0, 0, 0,
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    #[command(flatten)]
+    pub telemetry_config: TelemetryConfig,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct TelemetryConfig {
+    /// OTLP gRPC endpoint traces are exported to, e.g. http://localhost:4317.
+    /// Traces are not exported when unset
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT", global = true)]
+    pub otlp_endpoint: Option<String>,
+
+    /// Service name reported on exported traces
+    #[arg(
+        long,
+        env = "OTEL_SERVICE_NAME",
+        default_value = "homelab-aws-creds",
+        global = true
+    )]
+    pub otel_service_name: String,
+
+    /// Fraction of traces started by this process that are exported. Traces
+    /// continued from an incoming traceparent follow the caller's sampling decision
+    #[arg(long, env, default_value = "1.0", global = true)]
+    pub otel_sample_ratio: f64,
 }
 
 #[derive(Clone, Subcommand, Debug)]
 pub enum Commands {
     Agent(AgentConfig),
     Webhook(WebhookConfig),
+    /// Forwards connections on a loopback port to the agent's Unix socket. Runs as a
+    /// sidecar in pods that cannot reach the agent address
+    Proxy(ProxyConfig),
+    /// Prints the CustomResourceDefinitions as YAML
+    Crd,
+    /// Validates a role mapping config and prints the problems found. Fails when the
+    /// agent and webhook would reject the config
+    ValidateMappings(ValidateMappingsConfig),
+    /// Prints the JSON Schema of role mapping files
+    MappingsSchema,
     #[cfg(target_os = "linux")]
     Netlink,
 }
@@ -33,6 +68,219 @@ pub struct AgentConfig {
     /// Server listener for agent
     #[arg(long, default_value = "169.254.170.23:8080")]
     pub server_address: String,
+
+    /// The amount of time in seconds a denied identity or failing role is remembered
+    /// before TokenReview and STS are tried again. Set to 0 to disable
+    #[arg(long, env, default_value = "10")]
+    pub negative_cache_ttl: u64,
+
+    /// Add namespace and service account labels to credential request metrics. This
+    /// increases metric cardinality with the number of mapped service accounts
+    #[arg(long, env, default_value = "false", action = clap::ArgAction::Set)]
+    pub metrics_identity_labels: bool,
+
+    #[command(flatten)]
+    pub unix_socket_config: UnixSocketConfig,
+
+    #[command(flatten)]
+    pub agent_tls_config: AgentTlsConfig,
+
+    #[command(flatten)]
+    pub sts_config: StsConfig,
+
+    #[command(flatten)]
+    pub audit_config: AuditConfig,
+
+    #[command(flatten)]
+    pub events_config: EventsConfig,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct AgentTlsConfig {
+    /// Serve the agent address over HTTPS with this certificate. The certificate must
+    /// include the agent address as an IP SAN. Reloaded when the file changes
+    #[arg(long, env, requires = "agent_tls_key")]
+    pub agent_tls_cert: Option<PathBuf>,
+
+    /// Private key for the agent certificate
+    #[arg(long, env, requires = "agent_tls_cert")]
+    pub agent_tls_key: Option<PathBuf>,
+
+    /// CA bundle that issued the agent certificate
+    #[arg(long, env, requires_all = ["agent_tls_cert", "agent_ca_export_path"])]
+    pub agent_tls_ca: Option<PathBuf>,
+
+    /// Copy the CA bundle to this path, e.g. in a hostPath directory the webhook
+    /// mounts into pods
+    #[arg(long, env, requires = "agent_tls_ca")]
+    pub agent_ca_export_path: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct UnixSocketConfig {
+    /// Additionally serve credentials on a Unix socket at this path, e.g. in a
+    /// hostPath directory shared with pods
+    #[arg(long, env)]
+    pub unix_socket_path: Option<PathBuf>,
+
+    /// Permissions of the Unix socket in octal
+    #[arg(long, env, default_value = "0666", value_parser = parse_mode)]
+    pub unix_socket_mode: u32,
+
+    /// Owner of the Unix socket as uid or uid:gid
+    #[arg(long, env, value_parser = parse_owner)]
+    pub unix_socket_owner: Option<SocketOwner>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOwner {
+    pub uid: u32,
+    pub gid: Option<u32>,
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("invalid socket mode {mode}, expected octal permissions"))
+}
+
+fn parse_owner(owner: &str) -> Result<SocketOwner, String> {
+    let parse_id = |id: &str| {
+        id.parse::<u32>()
+            .map_err(|_| format!("invalid socket owner {owner}, expected uid or uid:gid"))
+    };
+    match owner.split_once(':') {
+        Some((uid, gid)) => Ok(SocketOwner {
+            uid: parse_id(uid)?,
+            gid: Some(parse_id(gid)?),
+        }),
+        None => Ok(SocketOwner {
+            uid: parse_id(owner)?,
+            gid: None,
+        }),
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ProxyConfig {
+    /// Path of the agent's Unix socket
+    #[arg(long, env, default_value = "/var/run/homelab-aws-creds/agent.sock")]
+    pub socket_path: PathBuf,
+
+    /// Loopback address the proxy listens on
+    #[arg(long, env, default_value = "127.0.0.1:8181")]
+    pub listen_address: String,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ValidateMappingsConfig {
+    /// Path to the role mapping config, a file or a directory of files
+    #[arg(long, env)]
+    pub role_mapping_path: PathBuf,
+
+    /// Format the problems are printed in
+    #[arg(long, env, value_enum, default_value = "text")]
+    pub output: DiagnosticsFormat,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticsFormat {
+    /// One line per problem prefixed with the file and line
+    Text,
+    /// A JSON array of problems with their severity, line, path and message
+    Json,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct EventsConfig {
+    /// Publish Kubernetes Events on pods whose credential requests fail
+    #[arg(long, env, default_value = "true", action = clap::ArgAction::Set)]
+    pub failure_events: bool,
+
+    /// Minimum time in seconds between events for the same pod and reason
+    #[arg(long, env, default_value = "60")]
+    pub failure_event_interval: u64,
+
+    /// Name of the node the agent runs on. Used to attribute failures to pods when
+    /// the pod cannot be identified from its token
+    #[arg(long, env)]
+    pub node_name: Option<String>,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct AuditConfig {
+    /// Where audit events for credential requests are written
+    #[arg(long, env, value_enum, default_value = "none")]
+    pub audit_sink: AuditSinkKind,
+
+    /// Path of the audit log when using the file sink
+    #[arg(long, env)]
+    pub audit_file_path: Option<PathBuf>,
+
+    /// Size in bytes at which the audit log file is rotated
+    #[arg(long, env, default_value = "104857600")]
+    pub audit_file_max_size: u64,
+
+    /// Number of rotated audit log files to keep
+    #[arg(long, env, default_value = "5")]
+    pub audit_file_max_backups: usize,
+
+    /// URL audit events are posted to when using the webhook sink
+    #[arg(long, env)]
+    pub audit_webhook_url: Option<String>,
+
+    /// Number of audit events buffered before new events are dropped
+    #[arg(long, env, default_value = "1024")]
+    pub audit_buffer_size: usize,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditSinkKind {
+    None,
+    Stdout,
+    File,
+    Webhook,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct StsConfig {
+    /// Maximum number of retries for throttled or transient STS errors
+    #[arg(long, env, default_value = "3")]
+    pub sts_max_retries: u32,
+
+    /// Base delay for exponential backoff between STS retries in milliseconds
+    #[arg(long, env, default_value = "100")]
+    pub sts_retry_base_delay_ms: u64,
+
+    /// Maximum delay between STS retries in milliseconds
+    #[arg(long, env, default_value = "2000")]
+    pub sts_retry_max_delay_ms: u64,
+
+    /// Consecutive AssumeRole failures for a role before requests for it fail fast.
+    /// Set to 0 to disable the circuit breaker
+    #[arg(long, env, default_value = "5")]
+    pub sts_circuit_breaker_threshold: u32,
+
+    /// The amount of time in seconds a role's circuit stays open before STS is tried again
+    #[arg(long, env, default_value = "30")]
+    pub sts_circuit_breaker_cooldown: u64,
+
+    /// Cached credentials are refreshed when they expire within this many seconds
+    #[arg(long, env, default_value = "900")]
+    pub credential_refresh_window: u64,
+
+    /// Minimum remaining lifetime in seconds for cached credentials to still be served
+    /// when refreshing them from STS fails with a throttling or transient error
+    #[arg(long, env, default_value = "60")]
+    pub stale_credential_min_remaining: u64,
+
+    /// Role assumed to revoke the older sessions of roles that are no longer mapped to
+    /// any service account, by attaching a policy denying credentials issued before
+    /// they were unmapped. Needs iam:PutRolePolicy on the mapped roles. Cached
+    /// credentials of unmapped roles are evicted either way
+    #[arg(long, env)]
+    pub revoke_sessions_role_arn: Option<String>,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -57,10 +305,52 @@ pub struct WebhookConfig {
     #[arg(long, default_value = "169.254.170.23:8080")]
     pub agent_address: String,
 
+    /// How mutated pods reach the agent
+    #[arg(long, env, value_enum, default_value = "tcp")]
+    pub agent_endpoint: AgentEndpointMode,
+
+    /// Path of the agent's Unix socket on the host. Its directory is mounted into pods
+    /// at the same path when using the socket or proxy endpoint
+    #[arg(long, env, default_value = "/var/run/homelab-aws-creds/agent.sock")]
+    pub agent_socket_path: PathBuf,
+
+    /// Image of the proxy sidecar injected when using the proxy endpoint
+    #[arg(long, env, required_if_eq("agent_endpoint", "proxy"))]
+    pub proxy_image: Option<String>,
+
+    /// Loopback port the proxy sidecar listens on
+    #[arg(long, env, default_value = "8181")]
+    pub proxy_port: u16,
+
+    /// Point pods at the agent over HTTPS. Only applies to the tcp agent endpoint as
+    /// the other endpoints never leave the pod or node unencrypted
+    #[arg(long, env, default_value = "false", action = clap::ArgAction::Set)]
+    pub agent_tls: bool,
+
+    /// Path of the CA bundle for the agent's certificates on the host. Its directory is
+    /// mounted into pods at the same path when using agent TLS
+    #[arg(long, env, default_value = "/var/run/homelab-aws-creds/ca.crt")]
+    pub agent_ca_path: PathBuf,
+
+    /// Also set AWS_CA_BUNDLE to the agent's CA bundle. Some SDKs use it for every AWS
+    /// API call, so only enable it when the bundle contains the public roots as well
+    #[arg(long, env, default_value = "false", action = clap::ArgAction::Set)]
+    pub agent_aws_ca_bundle: bool,
+
     #[command(flatten)]
     pub common_config: CommonConfig,
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AgentEndpointMode {
+    /// Containers call the agent address directly
+    Tcp,
+    /// The agent's socket is mounted into containers for clients that can use it
+    Socket,
+    /// A proxy sidecar forwards a loopback port to the agent's socket
+    Proxy,
+}
+
 #[derive(Parser, Debug, Clone)]
 pub struct CommonConfig {
     /// Metrics listener
@@ -72,7 +362,106 @@ pub struct CommonConfig {
     #[arg(long, default_value = "0")]
     pub ready_grace_period: u64,
 
-    // Path to the role mapping config
+    /// Interval in seconds between checks of external dependencies reported by readyz
+    #[arg(long, env, default_value = "30")]
+    pub readiness_check_interval: u64,
+
+    /// The amount of time in seconds without progress on the runtime before livez fails
+    #[arg(long, env, default_value = "30")]
+    pub liveness_timeout: u64,
+
+    /// Path to the role mapping config. When it is a directory every YAML, JSON and
+    /// TOML file in it is merged
     #[arg(long, env)]
     pub role_mapping_path: PathBuf,
+
+    /// Watch namespaces so mappings can select them with a namespaceSelector
+    #[arg(long, env, default_value = "false", action = clap::ArgAction::Set)]
+    pub namespace_selectors: bool,
+
+    /// Watch service accounts so they can claim roles with the eks.amazonaws.com/role-arn
+    /// annotation, as far as the annotation policies in the mapping config allow
+    #[arg(long, env, default_value = "false", action = clap::ArgAction::Set)]
+    pub service_account_annotations: bool,
+
+    /// Also map service accounts with AWSRoleBinding resources
+    #[arg(long, env, default_value = "false", action = clap::ArgAction::Set)]
+    pub role_bindings: bool,
+
+    /// Update the status conditions of AWSRoleBinding resources. Only one component
+    /// should write the status
+    #[arg(long, env, default_value = "false", action = clap::ArgAction::Set)]
+    pub role_binding_status: bool,
+
+    /// Path to a file containing the bearer token required by the /debug routes on
+    /// the metrics listener. Debug routes are disabled when unset
+    #[arg(long, env)]
+    pub debug_token_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub server_config: ServerConfig,
+}
+
+impl CommonConfig {
+    /// Whether any mapping source needs access to the Kubernetes API
+    pub fn watches_cluster(&self) -> bool {
+        self.namespace_selectors || self.service_account_annotations || self.role_bindings
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ServerConfig {
+    /// Maximum time in seconds to handle a request before it fails with 408
+    #[arg(long, env, default_value = "10")]
+    pub request_timeout: u64,
+
+    /// Maximum size in bytes of request headers
+    #[arg(long, env, default_value = "417792", value_parser = clap::value_parser!(u32).range(8192..))]
+    pub max_header_size: u32,
+
+    /// Maximum size in bytes of request bodies. Larger requests fail with 413
+    #[arg(long, env, default_value = "2097152")]
+    pub max_body_size: usize,
+
+    /// Maximum number of requests handled at once. Requests beyond the limit are
+    /// rejected with 503 instead of queueing. Set to 0 to disable
+    #[arg(long, env, default_value = "1024")]
+    pub max_concurrent_requests: usize,
+
+    /// Keep HTTP/1 connections open between requests
+    #[arg(long, env, default_value = "true", action = clap::ArgAction::Set)]
+    pub http_keep_alive: bool,
+
+    /// Maximum time in seconds in flight requests are given to complete during
+    /// shutdown before their connections are closed. Together with the ready grace
+    /// period this should stay below the pod's termination grace period
+    #[arg(long, env, default_value = "25")]
+    pub shutdown_drain_timeout: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_options() {
+        assert_eq!(parse_mode("0660"), Ok(0o660));
+        assert!(parse_mode("0999").is_err());
+        assert!(parse_mode("01777").is_err());
+        assert_eq!(
+            parse_owner("1000:2000"),
+            Ok(SocketOwner {
+                uid: 1000,
+                gid: Some(2000)
+            })
+        );
+        assert_eq!(
+            parse_owner("1000"),
+            Ok(SocketOwner {
+                uid: 1000,
+                gid: None
+            })
+        );
+        assert!(parse_owner("root").is_err());
+    }
 }
@@ -0,0 +1,58 @@
+use kube::{CustomResource, CustomResourceExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Binds a service account in the binding's namespace to an AWS role. Merged with the
+/// mapping file, which takes precedence when both map the same service account, and
+/// limited to the roles its annotation policies allow in the namespace.
+#[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[kube(
+    group = "homelab-aws-creds.io",
+    version = "v1alpha1",
+    kind = "AWSRoleBinding",
+    namespaced,
+    status = "AWSRoleBindingStatus",
+    shortname = "awsrb",
+    printcolumn = r#"{"name":"Service Account","type":"string","jsonPath":".spec.serviceAccount"}"#,
+    printcolumn = r#"{"name":"Role","type":"string","jsonPath":".spec.awsRole"}"#,
+    printcolumn = r#"{"name":"Ready","type":"string","jsonPath":".status.conditions[?(@.type==\"Ready\")].status"}"#,
+    printcolumn = r#"{"name":"Age","type":"date","jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct AWSRoleBindingSpec {
+    pub service_account: String,
+    pub aws_role: String,
+    /// Session name used when assuming the role, defaults to `<namespace>-<serviceAccount>`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(length(min = 2, max = 64))]
+    pub session_name: Option<String>,
+    /// Lifetime of the issued credentials in seconds, defaults to 3600
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(range(min = 900, max = 43200))]
+    pub duration_seconds: Option<i32>,
+}
+
+/// Prints the CustomResourceDefinitions for installing them with kubectl or Helm
+pub fn print_crds() -> Result<(), anyhow::Error> {
+    print!("{}", serde_yaml_ng::to_string(&AWSRoleBinding::crd())?);
+    Ok(())
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize, JsonSchema)]
+pub struct AWSRoleBindingStatus {
+    #[serde(default)]
+    pub conditions: Vec<BindingCondition>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BindingCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub status: String,
+    pub reason: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    pub last_transition_time: String,
+}
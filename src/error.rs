@@ -1,3 +1,4 @@
+use std::fmt::Display;
 use std::time::SystemTimeError;
 
 use thiserror::Error;
@@ -10,6 +11,12 @@ pub enum Error {
     #[error("aws error: {0}")]
     AwsError(String),
 
+    #[error("aws sts {kind} error: {message}")]
+    AssumeRoleError { kind: StsErrorKind, message: String },
+
+    #[error("failing fast for role {0}: too many consecutive AssumeRole failures")]
+    CircuitOpen(String),
+
     #[error("time conversion error: {0}")]
     TimeError(#[from] SystemTimeError),
 
@@ -19,6 +26,9 @@ pub enum Error {
     #[error("error serializing/deserializing: {0}")]
     SerDeError(#[from] serde_yaml_ng::Error),
 
+    #[error("error serializing/deserializing json: {0}")]
+    JsonError(#[from] serde_json::Error),
+
     #[error("{0}")]
     KubeError(#[from] kube::error::Error),
 
@@ -31,6 +41,12 @@ pub enum Error {
     #[error("{0}")]
     RoleMappingError(String),
 
+    #[error("audit error: {0}")]
+    AuditError(String),
+
+    #[error("telemetry error: {0}")]
+    TelemetryError(String),
+
     #[error("notify error: {0}")]
     NotifyError(#[from] notify::Error),
 
@@ -38,3 +54,35 @@ pub enum Error {
     #[error("rtnetlink error: {0}")]
     NetlinkError(#[from] rtnetlink::Error),
 }
+
+/// Broad classes of STS failures used to decide whether a request is retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StsErrorKind {
+    Throttling,
+    Transient,
+    AccessDenied,
+    MalformedPolicy,
+    Other,
+}
+
+impl StsErrorKind {
+    pub fn is_retriable(&self) -> bool {
+        matches!(self, StsErrorKind::Throttling | StsErrorKind::Transient)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StsErrorKind::Throttling => "throttling",
+            StsErrorKind::Transient => "transient",
+            StsErrorKind::AccessDenied => "access_denied",
+            StsErrorKind::MalformedPolicy => "malformed_policy",
+            StsErrorKind::Other => "other",
+        }
+    }
+}
+
+impl Display for StsErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
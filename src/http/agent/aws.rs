@@ -1,30 +1,57 @@
+use std::fmt::{Display, Formatter};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::error::Error;
+use super::retry::{classify_sdk_error, Backoff, CircuitBreaker};
+use crate::config::StsConfig;
+use crate::error::{Error, StsErrorKind};
+use crate::http::debug::{CacheEntry, DebugCache};
 use aws_config::meta::region::RegionProviderChain;
+use aws_config::retry::RetryConfig;
+use aws_sdk_sts::error::DisplayErrorContext;
+use aws_sdk_sts::operation::assume_role::AssumeRoleOutput;
 use aws_sdk_sts::Client as StsClient;
 use aws_smithy_types::DateTime;
+use futures_util::future::BoxFuture;
 use serde::{Serialize, Serializer};
 use tokio::sync::RwLock;
-use tracing::info;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+const CACHE_METRICS_INTERVAL: Duration = Duration::from_secs(15);
+const DEFAULT_DURATION: i32 = 3600;
 
 #[derive(Clone)]
 pub(crate) struct AwsState {
     sts_client: StsClient,
     credential_cache: Arc<RwLock<Vec<CachedCredential>>>,
+    backoff: Backoff,
+    circuit_breaker: CircuitBreaker,
+    refresh_window: u64,
+    stale_min_remaining: u64,
 }
 
 impl AwsState {
-    pub async fn new() -> Self {
+    pub async fn new(cfg: &StsConfig) -> Self {
         let region_provider = RegionProviderChain::default_provider();
-        let config = aws_config::from_env().region(region_provider).load().await;
+        // retries are handled by assume_role so errors can be classified and
+        // counted against the role's circuit breaker
+        let config = aws_config::from_env()
+            .region(region_provider)
+            .retry_config(RetryConfig::disabled())
+            .load()
+            .await;
         let sts_client = StsClient::new(&config);
         let credential_cache = Arc::new(RwLock::new(vec![]));
 
         Self {
             sts_client,
             credential_cache,
+            backoff: Backoff::new(cfg),
+            circuit_breaker: CircuitBreaker::new(cfg),
+            refresh_window: cfg.credential_refresh_window,
+            // served credentials must have at least some lifetime left
+            stale_min_remaining: cfg.stale_credential_min_remaining.max(1),
         }
     }
 
@@ -32,26 +59,40 @@ impl AwsState {
         &self,
         role: String,
         session_name: String,
-    ) -> Result<TemporaryCredential, Error> {
-        if let Some(creds) = self.get_cached_credential(&role).await {
-            info!("using cached credentials for role {}", role);
-            return Ok(TemporaryCredential {
-                version: 1,
-                access_key_id: creds.access_key_id,
-                secret_access_key: creds.secret_access_key,
-                session_token: creds.session_token,
-                expiration: creds.expiration,
-            });
-        }
-        let creds = self
-            .sts_client
-            .assume_role()
-            .set_role_session_name(Some(session_name))
-            .set_role_arn(Some(role.clone()))
-            .set_duration_seconds(Some(3600))
-            .send()
+        duration_seconds: Option<i32>,
+    ) -> Result<(TemporaryCredential, CredentialSource), Error> {
+        let session = RoleSession {
+            role,
+            session_name,
+            duration_seconds: duration_seconds.unwrap_or(DEFAULT_DURATION),
+        };
+        if let Some(creds) = self
+            .get_cached_credential(&session, self.refresh_window)
             .await
-            .map_err(|e| Error::AwsError(e.to_string()))?;
+        {
+            info!("using cached credentials for {}", session);
+            metrics::counter!("credential_cache_hits").increment(1);
+            return Ok((
+                TemporaryCredential {
+                    version: 1,
+                    access_key_id: creds.access_key_id,
+                    secret_access_key: creds.secret_access_key,
+                    session_token: creds.session_token,
+                    expiration: creds.expiration,
+                },
+                CredentialSource::Cache,
+            ));
+        }
+        metrics::counter!("credential_cache_misses").increment(1);
+        let creds = match self.assume_role(&session).await {
+            Ok(creds) => creds,
+            Err(e) => {
+                return self
+                    .get_stale_credential(&session, e)
+                    .await
+                    .map(|creds| (creds, CredentialSource::StaleCache))
+            }
+        };
 
         let creds = creds
             .credentials()
@@ -65,17 +106,174 @@ impl AwsState {
         };
 
         self.add_cached_credential(CachedCredential {
-            role,
+            session,
             credential: tc.clone(),
         })
         .await;
-        Ok(tc)
+        Ok((tc, CredentialSource::Sts))
+    }
+
+    /// State with an STS client that is not configured with credentials
+    #[cfg(test)]
+    pub(crate) fn unconfigured() -> Self {
+        let config = aws_sdk_sts::Config::builder()
+            .behavior_version(aws_sdk_sts::config::BehaviorVersion::latest())
+            .region(aws_sdk_sts::config::Region::new("us-east-1"))
+            .build();
+        let cfg = StsConfig {
+            sts_max_retries: 0,
+            sts_retry_base_delay_ms: 100,
+            sts_retry_max_delay_ms: 1000,
+            sts_circuit_breaker_threshold: 2,
+            sts_circuit_breaker_cooldown: 30,
+            credential_refresh_window: 900,
+            stale_credential_min_remaining: 60,
+            revoke_sessions_role_arn: None,
+        };
+        Self {
+            sts_client: StsClient::from_conf(config),
+            credential_cache: Arc::new(RwLock::new(vec![])),
+            backoff: Backoff::new(&cfg),
+            circuit_breaker: CircuitBreaker::new(&cfg),
+            refresh_window: cfg.credential_refresh_window,
+            stale_min_remaining: cfg.stale_credential_min_remaining,
+        }
+    }
+
+    /// Caches credentials of the role expiring in five minutes
+    #[cfg(test)]
+    pub(crate) async fn cache_credential(&self, role: &str) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        self.add_cached_credential(CachedCredential {
+            session: RoleSession::test(role),
+            credential: TemporaryCredential {
+                version: 1,
+                access_key_id: "id".into(),
+                secret_access_key: "secret".into(),
+                session_token: "token".into(),
+                expiration: DateTime::from_secs(now.as_secs() as i64 + 300),
+            },
+        })
+        .await;
+    }
+
+    /// Verifies the agent's own AWS credentials are valid with GetCallerIdentity
+    pub(crate) async fn caller_identity(&self) -> Result<String, Error> {
+        let identity = self
+            .sts_client
+            .get_caller_identity()
+            .send()
+            .await
+            .map_err(|e| Error::AwsError(DisplayErrorContext(&e).to_string()))?;
+        Ok(format!(
+            "authenticated as {}",
+            identity.arn().unwrap_or("unknown")
+        ))
+    }
+
+    // Calls AssumeRole, retrying throttled and transient errors with backoff. Failures
+    // after retries are exhausted are counted against the session's circuit breaker.
+    #[tracing::instrument(name = "sts_assume_role", skip(self, session), fields(role = %session.role, attempts))]
+    async fn assume_role(&self, session: &RoleSession) -> Result<AssumeRoleOutput, Error> {
+        if !self.circuit_breaker.allow(session) {
+            return Err(Error::CircuitOpen(session.role.clone()));
+        }
+        let mut attempt = 0;
+        loop {
+            tracing::Span::current().record("attempts", attempt + 1);
+            let start = Instant::now();
+            let result = self
+                .sts_client
+                .assume_role()
+                .set_role_session_name(Some(session.session_name.clone()))
+                .set_role_arn(Some(session.role.clone()))
+                .set_duration_seconds(Some(session.duration_seconds))
+                .send()
+                .await;
+            let err = match result {
+                Ok(output) => {
+                    metrics::histogram!("sts_assume_role_duration_seconds", "outcome" => "success")
+                        .record(start.elapsed().as_secs_f64());
+                    self.circuit_breaker.record_success(session);
+                    return Ok(output);
+                }
+                Err(e) => e,
+            };
+            let kind = classify_sdk_error(&err);
+            metrics::histogram!("sts_assume_role_duration_seconds", "outcome" => kind.as_str())
+                .record(start.elapsed().as_secs_f64());
+            if kind.is_retriable() && attempt < self.backoff.max_retries() {
+                let delay = self.backoff.delay(attempt);
+                warn!(
+                    "AssumeRole for {} failed with {} error, retrying in {}ms",
+                    session,
+                    kind,
+                    delay.as_millis()
+                );
+                attempt += 1;
+                sleep(delay).await;
+                continue;
+            }
+            self.circuit_breaker.record_failure(session);
+            return Err(Error::AssumeRoleError {
+                kind,
+                message: DisplayErrorContext(&err).to_string(),
+            });
+        }
     }
-    async fn get_cached_credential(&self, role: &str) -> Option<TemporaryCredential> {
+
+    // Falls back to cached credentials inside the refresh window when refreshing them
+    // failed because STS is unavailable, as long as they are not about to expire.
+    // Credentials of a role the agent was denied are evicted instead, so tightening
+    // its trust policy stops issuance right away.
+    async fn get_stale_credential(
+        &self,
+        session: &RoleSession,
+        refresh_err: Error,
+    ) -> Result<TemporaryCredential, Error> {
+        let role = session.role.as_str();
+        match refresh_err {
+            Error::CircuitOpen(_) => {}
+            Error::AssumeRoleError { kind, .. } if kind.is_retriable() => {}
+            Error::AssumeRoleError {
+                kind: StsErrorKind::AccessDenied | StsErrorKind::MalformedPolicy,
+                ..
+            } => {
+                if self.evict(role).await {
+                    warn!(
+                        "evicted cached credentials for role {}: {}",
+                        role, refresh_err
+                    );
+                }
+                return Err(refresh_err);
+            }
+            _ => return Err(refresh_err),
+        }
+        let Some(creds) = self
+            .get_cached_credential(session, self.stale_min_remaining)
+            .await
+        else {
+            return Err(refresh_err);
+        };
+        warn!(
+            "refreshing credentials for {} failed, serving cached credentials expiring at {}: {}",
+            session, creds.expiration, refresh_err
+        );
+        metrics::counter!("stale_credentials_served").increment(1);
+        Ok(creds)
+    }
+
+    async fn get_cached_credential(
+        &self,
+        session: &RoleSession,
+        min_remaining: u64,
+    ) -> Option<TemporaryCredential> {
         let guard = self.credential_cache.read().await;
         let now = SystemTime::now();
         for cached_cred in guard.iter() {
-            if role == cached_cred.role && !expired(&cached_cred.credential.expiration, now).ok()? {
+            if *session == cached_cred.session
+                && !expired(&cached_cred.credential.expiration, now, min_remaining).ok()?
+            {
                 return Some(cached_cred.credential.clone());
             }
         }
@@ -84,32 +282,140 @@ impl AwsState {
     async fn add_cached_credential(&self, cached_cred: CachedCredential) {
         let mut guard = self.credential_cache.write().await;
         for cred in guard.iter_mut() {
-            if cred.role == cached_cred.role {
-                info!("updating credentials for role {}", cached_cred.role);
+            if cred.session == cached_cred.session {
+                info!("updating credentials for {}", cached_cred.session);
                 cred.credential = cached_cred.credential;
                 return;
             }
         }
-        drop(guard);
-        info!("caching credentials for role {}", cached_cred.role);
-        self.credential_cache.write().await.push(cached_cred);
+        info!("caching credentials for {}", cached_cred.session);
+        guard.push(cached_cred);
+        metrics::gauge!("credential_cache_size").set(guard.len() as f64);
+    }
+
+    /// Drops the cached credentials of every session of a role, returning whether there
+    /// were any
+    pub(crate) async fn evict(&self, role: &str) -> bool {
+        let mut guard = self.credential_cache.write().await;
+        let cached = guard.len();
+        guard.retain(|cached_cred| cached_cred.session.role != role);
+        metrics::gauge!("credential_cache_size").set(guard.len() as f64);
+        guard.len() < cached
+    }
+
+    /// Periodically reports the size of the cache and the remaining lifetime of the
+    /// credentials expiring first. The lifetime is not labeled by role, as the series of
+    /// evicted roles would be reported until the agent restarts.
+    pub(crate) async fn report_cache_metrics(self) {
+        let mut interval = tokio::time::interval(CACHE_METRICS_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) else {
+                continue;
+            };
+            let guard = self.credential_cache.read().await;
+            metrics::gauge!("credential_cache_size").set(guard.len() as f64);
+            let time_left = guard
+                .iter()
+                .map(|cached_cred| cached_cred.credential.expiration.secs() - now.as_secs() as i64)
+                .min()
+                .unwrap_or_default();
+            metrics::gauge!("credential_cache_ttl_seconds").set(time_left.max(0) as f64);
+        }
+    }
+}
+
+impl DebugCache for AwsState {
+    fn name(&self) -> &'static str {
+        "credentials"
+    }
+
+    fn entries(&self) -> BoxFuture<'_, Vec<CacheEntry>> {
+        Box::pin(async move {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default();
+            self.credential_cache
+                .read()
+                .await
+                .iter()
+                .map(|cached_cred| CacheEntry {
+                    key: cached_cred.session.role.clone(),
+                    session_name: Some(cached_cred.session.session_name.clone()),
+                    reason: None,
+                    expires_in_seconds: cached_cred.credential.expiration.secs() - now,
+                })
+                .collect()
+        })
+    }
+
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            self.credential_cache.write().await.clear();
+            metrics::gauge!("credential_cache_size").set(0.0);
+        })
     }
 }
 
-fn expired(credential_expiration: &DateTime, now: SystemTime) -> Result<bool, Error> {
+// Credentials are considered expired when less than `min_remaining` seconds are left
+fn expired(
+    credential_expiration: &DateTime,
+    now: SystemTime,
+    min_remaining: u64,
+) -> Result<bool, Error> {
     let now_as_secs = now.duration_since(UNIX_EPOCH)?.as_secs();
     let credential_expiration = credential_expiration.secs();
     let time_left = credential_expiration.checked_sub_unsigned(now_as_secs);
     if let Some(time_left) = time_left {
-        Ok(time_left < 900)
+        Ok(time_left < min_remaining as i64)
     } else {
         Ok(false)
     }
 }
 
+/// Where credentials returned by [`AwsState::get_credentials`] came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CredentialSource {
+    Cache,
+    Sts,
+    StaleCache,
+}
+
+/// Role assumed with the session settings of a request. Credentials are cached per
+/// session, as the session name and duration differ between mappings and aliases of
+/// the same role and are what CloudTrail attributes the calls to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct RoleSession {
+    pub role: String,
+    pub session_name: String,
+    pub duration_seconds: i32,
+}
+
+impl RoleSession {
+    #[cfg(test)]
+    pub(crate) fn test(role: &str) -> Self {
+        Self {
+            role: role.into(),
+            session_name: "test".into(),
+            duration_seconds: DEFAULT_DURATION,
+        }
+    }
+}
+
+impl Display for RoleSession {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "role {} with session {} for {}s",
+            self.role, self.session_name, self.duration_seconds
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 struct CachedCredential {
-    role: String,
+    session: RoleSession,
     credential: TemporaryCredential,
 }
 
@@ -136,16 +442,83 @@ where
 mod tests {
     use super::*;
 
+    #[tokio::test]
+    async fn stale_credentials_only_when_sts_unavailable() {
+        let state = AwsState::unconfigured();
+        let role = "arn:aws:iam::123456789000:role/test";
+        let session = &RoleSession::test(role);
+        state.cache_credential(role).await;
+        let error = |kind| Error::AssumeRoleError {
+            kind,
+            message: kind.to_string(),
+        };
+
+        for err in [
+            error(StsErrorKind::Throttling),
+            error(StsErrorKind::Transient),
+            Error::CircuitOpen(role.into()),
+        ] {
+            assert!(state.get_stale_credential(session, err).await.is_ok());
+        }
+        assert!(state
+            .get_stale_credential(session, error(StsErrorKind::Other))
+            .await
+            .is_err());
+
+        // denied roles lose their cached credentials
+        assert!(state
+            .get_stale_credential(session, error(StsErrorKind::AccessDenied))
+            .await
+            .is_err());
+        assert!(state.get_cached_credential(session, 60).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn credentials_are_cached_per_session() {
+        let state = AwsState::unconfigured();
+        let role = "arn:aws:iam::123456789000:role/test";
+        state.cache_credential(role).await;
+        let session = RoleSession::test(role);
+        assert!(state.get_cached_credential(&session, 60).await.is_some());
+
+        let renamed = RoleSession {
+            session_name: "other".into(),
+            ..session.clone()
+        };
+        assert!(state.get_cached_credential(&renamed, 60).await.is_none());
+        let shorter = RoleSession {
+            duration_seconds: 900,
+            ..session.clone()
+        };
+        assert!(state.get_cached_credential(&shorter, 60).await.is_none());
+
+        // eviction drops every session of the role
+        state
+            .add_cached_credential(CachedCredential {
+                credential: state.get_cached_credential(&session, 60).await.unwrap(),
+                session: renamed.clone(),
+            })
+            .await;
+        assert!(state.evict(role).await);
+        assert!(state.get_cached_credential(&session, 60).await.is_none());
+        assert!(state.get_cached_credential(&renamed, 60).await.is_none());
+    }
+
     #[test]
     fn time_checks() {
         let now = SystemTime::now();
         let now_as_secs = now.duration_since(UNIX_EPOCH).unwrap().as_secs() + 1;
         let dt = DateTime::from_secs(now_as_secs as i64);
         // expired check
-        assert!(expired(&dt, now).unwrap());
+        assert!(expired(&dt, now, 900).unwrap());
 
         // not expired
         let dt = DateTime::from_secs(now_as_secs as i64 + 901);
-        assert!(!expired(&dt, now).unwrap());
+        assert!(!expired(&dt, now, 900).unwrap());
+
+        // inside the refresh window but still usable if a refresh fails
+        let dt = DateTime::from_secs(now_as_secs as i64 + 300);
+        assert!(expired(&dt, now, 900).unwrap());
+        assert!(!expired(&dt, now, 60).unwrap());
     }
 }
@@ -0,0 +1,212 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use k8s_openapi::api::core::v1::{ObjectReference, Pod};
+use kube::api::ListParams;
+use kube::runtime::events::{Event, EventType, Recorder, Reporter};
+use kube::{Api, Client as KubeClient, Resource};
+use tracing::{debug, error};
+
+use crate::audit::AuditEvent;
+use crate::config::EventsConfig;
+
+const REPORTING_CONTROLLER: &str = "homelab-aws-creds-agent";
+
+/// Publishes Kubernetes Events on pods whose credential requests fail so the reason
+/// shows up in `kubectl describe pod`. Events for the same pod and reason are
+/// published at most once per interval.
+#[derive(Clone)]
+pub(crate) struct FailureEvents {
+    client: KubeClient,
+    recorder: Recorder,
+    node_name: Option<String>,
+    rate_limiter: RateLimiter,
+}
+
+impl FailureEvents {
+    pub(crate) fn new(client: KubeClient, cfg: &EventsConfig) -> Self {
+        let reporter = Reporter {
+            controller: REPORTING_CONTROLLER.into(),
+            instance: cfg.node_name.clone(),
+        };
+        Self {
+            recorder: Recorder::new(client.clone(), reporter),
+            client,
+            node_name: cfg.node_name.clone(),
+            rate_limiter: RateLimiter::new(Duration::from_secs(cfg.failure_event_interval)),
+        }
+    }
+
+    /// Publishes an event for the failed request described by the audit event in the
+    /// background. Requests that failed before the pod could be identified from the
+    /// TokenReview are attributed to the pod with the peer's IP on this node.
+    pub(crate) fn publish(&self, request: &AuditEvent, reason: &str, note: String) {
+        let Some(reason) = event_reason(reason) else {
+            return;
+        };
+        let target = match (&request.namespace, &request.pod_name) {
+            (Some(namespace), Some(name)) => EventTarget::Pod(ObjectReference {
+                api_version: Some("v1".into()),
+                kind: Some("Pod".into()),
+                namespace: Some(namespace.clone()),
+                name: Some(name.clone()),
+                uid: request.pod_uid.clone(),
+                ..Default::default()
+            }),
+            _ => match (&request.peer_ip, &self.node_name) {
+                (Some(ip), Some(_)) => EventTarget::PeerIp(ip.clone()),
+                _ => return,
+            },
+        };
+        if !self.rate_limiter.allow(target.key(), reason) {
+            return;
+        }
+        let events = self.clone();
+        tokio::spawn(async move {
+            let reference = match target {
+                EventTarget::Pod(reference) => reference,
+                EventTarget::PeerIp(ip) => match events.find_pod_by_ip(&ip).await {
+                    Some(reference) => reference,
+                    None => return,
+                },
+            };
+            let event = Event {
+                type_: EventType::Warning,
+                reason: reason.into(),
+                note: Some(note),
+                action: "IssueCredentials".into(),
+                secondary: None,
+            };
+            if let Err(e) = events.recorder.publish(&event, &reference).await {
+                error!("failed to publish {} event: {}", reason, e);
+            }
+        });
+    }
+
+    // Only pods that are not using the host network are matched as those share the IP
+    async fn find_pod_by_ip(&self, ip: &str) -> Option<ObjectReference> {
+        let node_name = self.node_name.as_deref()?;
+        let api: Api<Pod> = Api::all(self.client.clone());
+        let params =
+            ListParams::default().fields(&format!("spec.nodeName={node_name},status.podIP={ip}"));
+        let pods = match api.list(&params).await {
+            Ok(pods) => pods,
+            Err(e) => {
+                error!("failed to look up pod with ip {}: {}", ip, e);
+                return None;
+            }
+        };
+        let mut pods = pods.items.into_iter().filter(|pod| {
+            !pod.spec
+                .as_ref()
+                .and_then(|spec| spec.host_network)
+                .unwrap_or_default()
+        });
+        match (pods.next(), pods.next()) {
+            (Some(pod), None) => Some(pod.object_ref(&())),
+            _ => {
+                debug!("no unique pod found for ip {}", ip);
+                None
+            }
+        }
+    }
+}
+
+/// Remembers when an event was last published for a pod and reason
+#[derive(Clone)]
+struct RateLimiter {
+    interval: Duration,
+    last_published: Arc<Mutex<HashMap<(String, &'static str), Instant>>>,
+}
+
+impl RateLimiter {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_published: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn allow(&self, key: String, reason: &'static str) -> bool {
+        self.allow_at(key, reason, Instant::now())
+    }
+
+    fn allow_at(&self, key: String, reason: &'static str, now: Instant) -> bool {
+        let mut last_published = self
+            .last_published
+            .lock()
+            .expect("failure events lock poisoned");
+        last_published.retain(|_, published| now.duration_since(*published) < self.interval);
+        match last_published.entry((key, reason)) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+}
+
+enum EventTarget {
+    Pod(ObjectReference),
+    PeerIp(String),
+}
+
+impl EventTarget {
+    fn key(&self) -> String {
+        match self {
+            EventTarget::Pod(reference) => reference.uid.clone().unwrap_or_else(|| {
+                format!(
+                    "{}/{}",
+                    reference.namespace.as_deref().unwrap_or_default(),
+                    reference.name.as_deref().unwrap_or_default()
+                )
+            }),
+            EventTarget::PeerIp(ip) => ip.clone(),
+        }
+    }
+}
+
+// Maps credential error reasons to Kubernetes Event reasons. Failures that are not
+// caused by the pod's configuration do not produce events.
+fn event_reason(reason: &str) -> Option<&'static str> {
+    match reason {
+        "invalid_token" => Some("InvalidServiceAccountToken"),
+        "unmapped_service_account" => Some("ServiceAccountNotMapped"),
+        "unmapped_role_alias" => Some("RoleAliasNotMapped"),
+        "sts_access_denied" | "sts_malformed_policy" => Some("AssumeRoleDenied"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_reasons() {
+        assert_eq!(
+            event_reason("unmapped_service_account"),
+            Some("ServiceAccountNotMapped")
+        );
+        assert_eq!(event_reason("sts_access_denied"), Some("AssumeRoleDenied"));
+        assert_eq!(event_reason("sts_throttling"), None);
+    }
+
+    #[test]
+    fn rate_limited_per_pod_and_reason() {
+        let limiter = RateLimiter::new(Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(limiter.allow_at("pod-a".into(), "AssumeRoleDenied", now));
+        assert!(!limiter.allow_at("pod-a".into(), "AssumeRoleDenied", now));
+        assert!(limiter.allow_at("pod-a".into(), "ServiceAccountNotMapped", now));
+        assert!(limiter.allow_at("pod-b".into(), "AssumeRoleDenied", now));
+        assert!(limiter.allow_at(
+            "pod-a".into(),
+            "AssumeRoleDenied",
+            now + Duration::from_secs(60)
+        ));
+    }
+}
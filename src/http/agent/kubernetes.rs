@@ -1,3 +1,5 @@
+use std::time::Instant;
+
 use crate::error::Error;
 use k8s_openapi::api::authentication::v1::{TokenReview, TokenReviewSpec, TokenReviewStatus};
 use k8s_openapi::apimachinery::pkg::apis::meta;
@@ -15,9 +17,29 @@ impl KubeState {
         Ok(Self { kube_client })
     }
 
+    pub(crate) fn client(&self) -> KubeClient {
+        self.kube_client.clone()
+    }
+
+    /// Verifies TokenReviews can be created by reviewing a token that is never valid
+    pub(crate) async fn token_review_reachable(&self) -> Result<String, Error> {
+        match self
+            .allowed_token("readiness-check".into())
+            .await?
+            .authenticated
+        {
+            Some(true) => Err(Error::OtherError(
+                "TokenReview unexpectedly authenticated readiness token".into(),
+            )),
+            _ => Ok("TokenReview reachable".into()),
+        }
+    }
+
     // Verifies if the token is allowed by make a TokenReview request to kubernetes API
+    #[tracing::instrument(name = "token_review", skip_all)]
     pub(crate) async fn allowed_token(&self, token: String) -> Result<TokenReviewStatus, Error> {
         let api: Api<TokenReview> = Api::all(self.kube_client.clone());
+        let start = Instant::now();
         let response = api
             .create(
                 &PostParams::default(),
@@ -33,8 +55,17 @@ impl KubeState {
                     ..Default::default()
                 },
             )
-            .await?;
-        if let Some(status) = response.status {
+            .await;
+        let outcome = match response {
+            Ok(ref r) if r.status.as_ref().and_then(|s| s.authenticated) == Some(true) => {
+                "authenticated"
+            }
+            Ok(_) => "unauthenticated",
+            Err(_) => "error",
+        };
+        metrics::histogram!("token_review_duration_seconds", "outcome" => outcome)
+            .record(start.elapsed().as_secs_f64());
+        if let Some(status) = response?.status {
             Ok(status)
         } else {
             Err(Error::OtherError(
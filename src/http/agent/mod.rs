@@ -1,48 +1,183 @@
 mod aws;
+mod events;
 mod kubernetes;
+mod negative_cache;
+mod retry;
+mod revocation;
 mod state;
 
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use crate::audit::Auditor;
 use crate::config::AgentConfig;
-use crate::http::{mappings, shutdown_server};
-use anyhow::{anyhow, Error};
+use crate::http::debug::DebugState;
+use crate::http::health::HealthChecks;
+use crate::http::tls::{create_tls_config, start_tls_watch, CaExport, CertificateCheck};
+use crate::http::unix::bind_unix_socket;
+use crate::http::{configure_http, drain_on_cancel, mappings};
+use anyhow::Error;
 use aws::AwsState;
+use axum_server::tls_rustls::RustlsConfig;
+use events::FailureEvents;
 use kubernetes::KubeState;
+use negative_cache::NegativeCache;
+use revocation::{Revocation, SessionRevoker};
 use state::{new_agent_router, AgentState};
-use tokio::select;
 use tokio_util::sync::CancellationToken;
 use tracing::info;
 
 pub(crate) async fn start_agent(
     cancel: CancellationToken,
     cfg: Arc<AgentConfig>,
+    health_checks: HealthChecks,
+    debug_state: DebugState,
 ) -> Result<(), Error> {
     let kube_state = KubeState::try_new().await?;
-    let aws_state = AwsState::new().await;
+    let aws_state = AwsState::new(&cfg.sts_config).await;
+    tokio::spawn(aws_state.clone().report_cache_metrics());
     let role_mappings =
         mappings::Mapping::try_new_from_file(cfg.common_config.role_mapping_path.clone()).await?;
 
-    info!("creating agent router");
-    let router = new_agent_router(AgentState::new(aws_state, kube_state, role_mappings));
-
-    let shutdown_cancel = cancel.clone();
-    let h = tokio::spawn(async move {
-        info!("agent listening on {}", cfg.server_address);
-        let listener = tokio::net::TcpListener::bind(&cfg.server_address).await?;
-        axum::serve(listener, router.into_make_service())
-            .with_graceful_shutdown(shutdown_server(shutdown_cancel))
-            .await
+    let check_interval = Duration::from_secs(cfg.common_config.readiness_check_interval);
+    health_checks.register(role_mappings.readiness_check());
+    role_mappings.watch_cluster(kube_state.client(), &cfg.common_config, &health_checks);
+    debug_state.set_mappings(role_mappings.clone());
+    debug_state.register_cache(aws_state.clone());
+    health_checks.spawn_periodic("aws_credentials", check_interval, {
+        let aws_state = aws_state.clone();
+        move || {
+            let aws_state = aws_state.clone();
+            async move { aws_state.caller_identity().await }
+        }
+    });
+    health_checks.spawn_periodic("token_review", check_interval, {
+        let kube_state = kube_state.clone();
+        move || {
+            let kube_state = kube_state.clone();
+            async move { kube_state.token_review_reachable().await }
+        }
     });
-    select! {
-        h = h => {
-                match h {
-                    Ok(Err(e)) => return Err(e.into()),
-                    Ok(Ok(_)) => {}
-                    Err(_) => return Err(anyhow!("join handle failure")),
-                }
-            },
-        _  = cancel.cancelled() => {}
-    }
+
+    let negative_cache = NegativeCache::new(Duration::from_secs(cfg.negative_cache_ttl));
+    tokio::spawn(
+        negative_cache
+            .clone()
+            .clear_on_reload(role_mappings.subscribe()),
+    );
+    debug_state.register_cache(negative_cache.clone());
+
+    let auditor = Auditor::try_new(&cfg.audit_config).await?;
+    let sessions = match cfg.sts_config.revoke_sessions_role_arn {
+        Some(ref admin_role) => Some(SessionRevoker::new(admin_role).await),
+        None => None,
+    };
+    tokio::spawn(
+        Revocation::new(aws_state.clone(), auditor.clone(), sessions)
+            .revoke_on_unmap(role_mappings.clone()),
+    );
+    let failure_events = cfg
+        .events_config
+        .failure_events
+        .then(|| FailureEvents::new(kube_state.client(), &cfg.events_config));
+
+    info!("creating agent router");
+    let router = new_agent_router(
+        AgentState {
+            aws_state,
+            kube_state,
+            role_mappings,
+            negative_cache,
+            auditor,
+            failure_events,
+            recent_errors: debug_state.recent_errors(),
+            metrics_identity_labels: cfg.metrics_identity_labels,
+        },
+        &cfg.common_config.server_config,
+    );
+
+    let server_cfg = &cfg.common_config.server_config;
+    let drain = Duration::from_secs(server_cfg.shutdown_drain_timeout);
+    let server_address: SocketAddr = cfg.server_address.parse()?;
+    let handle = axum_server::Handle::new();
+    tokio::spawn(drain_on_cancel(cancel.clone(), handle.clone(), drain));
+    let tls_config = agent_tls(&cfg, &health_checks).await?;
+    health_checks.initialised();
+
+    let unix_server = match cfg.unix_socket_config.unix_socket_path {
+        Some(ref path) => {
+            let listener = bind_unix_socket(path, &cfg.unix_socket_config)?;
+            let handle = axum_server::Handle::new();
+            tokio::spawn(drain_on_cancel(cancel, handle.clone(), drain));
+            let mut server = axum_server::from_unix(listener)?.handle(handle);
+            configure_http(server.http_builder(), server_cfg);
+            info!("agent listening on {:?}", path);
+            Some(server)
+        }
+        None => None,
+    };
+
+    let make_service = router
+        .clone()
+        .into_make_service_with_connect_info::<SocketAddr>();
+    let tcp = async {
+        match tls_config {
+            Some(tls_config) => {
+                info!("agent listening securely on {}", server_address);
+                let mut server =
+                    axum_server::bind_rustls(server_address, tls_config).handle(handle);
+                configure_http(server.http_builder(), server_cfg);
+                server.serve(make_service).await
+            }
+            None => {
+                info!("agent listening on {}", server_address);
+                let mut server = axum_server::bind(server_address).handle(handle);
+                configure_http(server.http_builder(), server_cfg);
+                server.serve(make_service).await
+            }
+        }
+    };
+    let unix = async {
+        match unix_server {
+            Some(server) => server.serve(router.into_make_service()).await,
+            None => Ok(()),
+        }
+    };
+    tokio::try_join!(tcp, unix)?;
     Ok(())
 }
+
+// Loads the agent's serving certificate when TLS is configured, exporting the CA
+// bundle for pods and reloading both when the certificate is renewed
+async fn agent_tls(
+    cfg: &AgentConfig,
+    health_checks: &HealthChecks,
+) -> Result<Option<RustlsConfig>, Error> {
+    let tls_cfg = &cfg.agent_tls_config;
+    let (Some(cert), Some(key)) = (&tls_cfg.agent_tls_cert, &tls_cfg.agent_tls_key) else {
+        return Ok(None);
+    };
+    let tls_config = create_tls_config(cert, key)?;
+    let cert_check = CertificateCheck::try_new(cert)?;
+    health_checks.register(cert_check.clone());
+    let ca_export = match (&tls_cfg.agent_tls_ca, &tls_cfg.agent_ca_export_path) {
+        (Some(source), Some(destination)) => {
+            let ca_export = CaExport {
+                source: source.clone(),
+                destination: destination.clone(),
+            };
+            ca_export.export().await?;
+            Some(ca_export)
+        }
+        _ => None,
+    };
+    tokio::spawn(start_tls_watch(
+        tls_config.clone(),
+        cert.clone(),
+        key.clone(),
+        cert_check,
+        ca_export,
+    ));
+    Ok(Some(tls_config))
+}
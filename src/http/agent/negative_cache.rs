@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_util::future::BoxFuture;
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+use tracing::{debug, info};
+
+use super::state::CredentialError;
+use crate::http::debug::{CacheEntry, DebugCache};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum NegativeKey {
+    /// sha256 of a token and the role alias it was presented for that was rejected or
+    /// belongs to an unmapped identity
+    Token(String),
+    /// role that AssumeRole refused to issue credentials for
+    Role(String),
+}
+
+impl NegativeKey {
+    pub(crate) fn token(token: &str, alias: Option<&str>) -> Self {
+        let mut digest = Sha256::new();
+        digest.update(token.as_bytes());
+        if let Some(alias) = alias {
+            digest.update(b"\0");
+            digest.update(alias.as_bytes());
+        }
+        NegativeKey::Token(format!("{:x}", digest.finalize()))
+    }
+}
+
+/// Short lived cache of authorization failures so retries from SDKs do not repeat
+/// the TokenReview and STS requests for an identity that is going to be denied
+#[derive(Clone)]
+pub(crate) struct NegativeCache {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<NegativeKey, (Instant, CredentialError)>>>,
+}
+
+impl NegativeCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &NegativeKey) -> Option<CredentialError> {
+        self.get_at(key, Instant::now())
+    }
+
+    fn get_at(&self, key: &NegativeKey, now: Instant) -> Option<CredentialError> {
+        let mut entries = self.entries.lock().expect("negative cache lock poisoned");
+        match entries.get(key) {
+            Some((expires, err)) if now < *expires => Some(err.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub(crate) fn insert(&self, key: NegativeKey, err: CredentialError) {
+        self.insert_at(key, err, Instant::now())
+    }
+
+    fn insert_at(&self, key: NegativeKey, err: CredentialError, now: Instant) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        debug!("caching failure for {:?}", key);
+        let mut entries = self.entries.lock().expect("negative cache lock poisoned");
+        entries.retain(|_, (expires, _)| now < *expires);
+        entries.insert(key, (now + self.ttl, err));
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries
+            .lock()
+            .expect("negative cache lock poisoned")
+            .clear();
+    }
+
+    /// Clears the cache whenever the mappings are reloaded so a fixed mapping
+    /// takes effect immediately
+    pub(crate) async fn clear_on_reload(self, mut reloaded: watch::Receiver<()>) {
+        while reloaded.changed().await.is_ok() {
+            info!("mappings reloaded, clearing negative cache");
+            self.clear();
+        }
+    }
+}
+
+impl DebugCache for NegativeCache {
+    fn name(&self) -> &'static str {
+        "negative"
+    }
+
+    fn entries(&self) -> BoxFuture<'_, Vec<CacheEntry>> {
+        let now = Instant::now();
+        let entries = self
+            .entries
+            .lock()
+            .expect("negative cache lock poisoned")
+            .iter()
+            .filter(|(_, (expires, _))| now < *expires)
+            .map(|(key, (expires, err))| CacheEntry {
+                key: match key {
+                    NegativeKey::Token(hash) => format!("token:{hash}"),
+                    NegativeKey::Role(role) => format!("role:{role}"),
+                },
+                session_name: None,
+                reason: Some(err.reason.to_string()),
+                expires_in_seconds: expires.duration_since(now).as_secs() as i64,
+            })
+            .collect();
+        Box::pin(async move { entries })
+    }
+
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        self.clear();
+        Box::pin(async {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credential_error() -> CredentialError {
+        CredentialError {
+            code: "401 Unauthorized".into(),
+            message: "role not found serviceaccount mappings".into(),
+            reason: "unmapped_service_account",
+        }
+    }
+
+    #[test]
+    fn entries_expire() {
+        let cache = NegativeCache::new(Duration::from_secs(5));
+        let key = NegativeKey::token("token", None);
+        let now = Instant::now();
+        cache.insert_at(key.clone(), credential_error(), now);
+
+        assert!(cache.get_at(&key, now + Duration::from_secs(4)).is_some());
+        assert!(cache
+            .get_at(&NegativeKey::token("other", None), now)
+            .is_none());
+        assert!(cache.get_at(&key, now + Duration::from_secs(5)).is_none());
+        // expired entries are evicted on lookup
+        assert!(cache.get_at(&key, now).is_none());
+    }
+
+    #[test]
+    fn zero_ttl_disables_cache() {
+        let cache = NegativeCache::new(Duration::ZERO);
+        let key = NegativeKey::Role("arn:aws:iam::123456789000:role/test".into());
+        cache.insert(key.clone(), credential_error());
+        assert!(cache.get(&key).is_none());
+    }
+
+    #[tokio::test]
+    async fn cleared_on_reload() {
+        let cache = NegativeCache::new(Duration::from_secs(60));
+        let key = NegativeKey::token("token", None);
+        cache.insert(key.clone(), credential_error());
+
+        let (tx, rx) = watch::channel(());
+        let handle = tokio::spawn(cache.clone().clear_on_reload(rx));
+        tx.send_replace(());
+        drop(tx);
+        handle.await.unwrap();
+        assert!(cache.get(&key).is_none());
+    }
+}
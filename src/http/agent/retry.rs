@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use aws_sdk_sts::error::{ProvideErrorMetadata, SdkError};
+use rand::Rng;
+use tracing::warn;
+
+use super::aws::RoleSession;
+use crate::config::StsConfig;
+use crate::error::StsErrorKind;
+
+pub(crate) fn classify_sdk_error<E>(err: &SdkError<E>) -> StsErrorKind
+where
+    E: ProvideErrorMetadata,
+{
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            StsErrorKind::Transient
+        }
+        SdkError::ServiceError(service_err) => {
+            let kind = classify_error_code(service_err.err().code());
+            if kind == StsErrorKind::Other && service_err.raw().status().is_server_error() {
+                StsErrorKind::Transient
+            } else {
+                kind
+            }
+        }
+        _ => StsErrorKind::Other,
+    }
+}
+
+fn classify_error_code(code: Option<&str>) -> StsErrorKind {
+    match code {
+        Some(
+            "Throttling"
+            | "ThrottlingException"
+            | "ThrottledException"
+            | "RequestThrottled"
+            | "RequestThrottledException"
+            | "RequestLimitExceeded"
+            | "TooManyRequestsException"
+            | "SlowDown",
+        ) => StsErrorKind::Throttling,
+        Some(
+            "InternalFailure"
+            | "InternalError"
+            | "ServiceUnavailable"
+            | "RequestTimeout"
+            | "RequestTimeoutException"
+            | "IDPCommunicationError",
+        ) => StsErrorKind::Transient,
+        Some(
+            "AccessDenied"
+            | "AccessDeniedException"
+            | "InvalidClientTokenId"
+            | "SignatureDoesNotMatch"
+            | "RegionDisabledException",
+        ) => StsErrorKind::AccessDenied,
+        Some("MalformedPolicyDocument" | "PackedPolicyTooLarge") => StsErrorKind::MalformedPolicy,
+        _ => StsErrorKind::Other,
+    }
+}
+
+/// Bounded exponential backoff using full jitter
+#[derive(Debug, Clone)]
+pub(crate) struct Backoff {
+    max_retries: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new(cfg: &StsConfig) -> Self {
+        Self {
+            max_retries: cfg.sts_max_retries,
+            base_delay: Duration::from_millis(cfg.sts_retry_base_delay_ms),
+            max_delay: Duration::from_millis(cfg.sts_retry_max_delay_ms),
+        }
+    }
+
+    pub(crate) fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    // upper bound of the sleep before retry number `attempt` (starting at 0)
+    fn ceiling(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        self.base_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self.ceiling(attempt).as_millis() as u64;
+        Duration::from_millis(rand::rng().random_range(0..=ceiling))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { since: Instant },
+}
+
+/// Tracks consecutive AssumeRole failures per role session and fails fast once a
+/// session crosses the failure threshold until the cooldown has passed
+#[derive(Debug, Clone)]
+pub(crate) struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    sessions: Arc<Mutex<HashMap<RoleSession, BreakerState>>>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(cfg: &StsConfig) -> Self {
+        Self {
+            threshold: cfg.sts_circuit_breaker_threshold,
+            cooldown: Duration::from_secs(cfg.sts_circuit_breaker_cooldown),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns true when a request for the session may be sent to STS
+    pub(crate) fn allow(&self, session: &RoleSession) -> bool {
+        self.allow_at(session, Instant::now())
+    }
+
+    fn allow_at(&self, session: &RoleSession, now: Instant) -> bool {
+        if self.threshold == 0 {
+            return true;
+        }
+        let mut sessions = self.sessions.lock().expect("circuit breaker lock poisoned");
+        match sessions.get(session) {
+            None | Some(BreakerState::Closed { .. }) => true,
+            // a trial request is already in flight, unless it was dropped without
+            // recording its result in which case another one is let through after
+            // the cooldown
+            Some(BreakerState::HalfOpen { since }) if now < *since + self.cooldown => false,
+            Some(BreakerState::Open { until }) if now < *until => false,
+            Some(BreakerState::HalfOpen { .. } | BreakerState::Open { .. }) => {
+                sessions.insert(session.clone(), BreakerState::HalfOpen { since: now });
+                true
+            }
+        }
+    }
+
+    pub(crate) fn record_success(&self, session: &RoleSession) {
+        let mut sessions = self.sessions.lock().expect("circuit breaker lock poisoned");
+        sessions.remove(session);
+    }
+
+    pub(crate) fn record_failure(&self, session: &RoleSession) {
+        self.record_failure_at(session, Instant::now())
+    }
+
+    fn record_failure_at(&self, session: &RoleSession, now: Instant) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut sessions = self.sessions.lock().expect("circuit breaker lock poisoned");
+        let failures = match sessions.get(session) {
+            Some(BreakerState::Closed { failures }) => failures + 1,
+            Some(BreakerState::HalfOpen { .. }) => self.threshold,
+            Some(BreakerState::Open { .. }) => return,
+            None => 1,
+        };
+        let state = if failures >= self.threshold {
+            warn!(
+                "opening circuit for {} for {}s after {} consecutive failures",
+                session,
+                self.cooldown.as_secs(),
+                failures
+            );
+            BreakerState::Open {
+                until: now + self.cooldown,
+            }
+        } else {
+            BreakerState::Closed { failures }
+        };
+        sessions.insert(session.clone(), state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sts_config() -> StsConfig {
+        StsConfig {
+            sts_max_retries: 3,
+            sts_retry_base_delay_ms: 100,
+            sts_retry_max_delay_ms: 1000,
+            sts_circuit_breaker_threshold: 2,
+            sts_circuit_breaker_cooldown: 30,
+            credential_refresh_window: 900,
+            stale_credential_min_remaining: 60,
+            revoke_sessions_role_arn: None,
+        }
+    }
+
+    #[test]
+    fn error_code_classification() {
+        assert_eq!(
+            classify_error_code(Some("Throttling")),
+            StsErrorKind::Throttling
+        );
+        assert_eq!(
+            classify_error_code(Some("AccessDenied")),
+            StsErrorKind::AccessDenied
+        );
+        assert_eq!(
+            classify_error_code(Some("MalformedPolicyDocument")),
+            StsErrorKind::MalformedPolicy
+        );
+        assert_eq!(
+            classify_error_code(Some("ServiceUnavailable")),
+            StsErrorKind::Transient
+        );
+        assert_eq!(classify_error_code(None), StsErrorKind::Other);
+        assert!(!StsErrorKind::AccessDenied.is_retriable());
+        assert!(StsErrorKind::Throttling.is_retriable());
+    }
+
+    #[test]
+    fn backoff_is_bounded() {
+        let backoff = Backoff::new(&sts_config());
+        assert_eq!(backoff.ceiling(0), Duration::from_millis(100));
+        assert_eq!(backoff.ceiling(2), Duration::from_millis(400));
+        assert_eq!(backoff.ceiling(10), Duration::from_millis(1000));
+        assert_eq!(backoff.ceiling(u32::MAX), Duration::from_millis(1000));
+        for attempt in 0..10 {
+            assert!(backoff.delay(attempt) <= Duration::from_millis(1000));
+        }
+    }
+
+    #[test]
+    fn circuit_breaker_transitions() {
+        let breaker = CircuitBreaker::new(&sts_config());
+        let role = &RoleSession::test("arn:aws:iam::123456789000:role/test");
+        let now = Instant::now();
+
+        assert!(breaker.allow_at(role, now));
+        breaker.record_failure_at(role, now);
+        assert!(breaker.allow_at(role, now));
+        breaker.record_failure_at(role, now);
+        // threshold reached, circuit is open
+        assert!(!breaker.allow_at(role, now));
+        assert!(!breaker.allow_at(role, now + Duration::from_secs(29)));
+
+        // cooldown elapsed, a single trial request is let through
+        let later = now + Duration::from_secs(30);
+        assert!(breaker.allow_at(role, later));
+        assert!(!breaker.allow_at(role, later));
+
+        // failed trial reopens the circuit
+        breaker.record_failure_at(role, later);
+        assert!(!breaker.allow_at(role, later));
+
+        let much_later = later + Duration::from_secs(30);
+        assert!(breaker.allow_at(role, much_later));
+        breaker.record_success(role);
+        assert!(breaker.allow_at(role, much_later));
+        assert!(breaker.allow_at(&RoleSession::test("other"), much_later));
+
+        // other sessions of the role have their own circuit
+        breaker.record_failure_at(role, much_later);
+        breaker.record_failure_at(role, much_later);
+        assert!(!breaker.allow_at(role, much_later));
+        let other_session = RoleSession {
+            session_name: "other".into(),
+            ..role.clone()
+        };
+        assert!(breaker.allow_at(&other_session, much_later));
+    }
+
+    #[test]
+    fn circuit_breaker_dropped_trial() {
+        let breaker = CircuitBreaker::new(&sts_config());
+        let role = &RoleSession::test("arn:aws:iam::123456789000:role/test");
+        let now = Instant::now();
+        breaker.record_failure_at(role, now);
+        breaker.record_failure_at(role, now);
+
+        // the trial is dropped without recording a result
+        let trial = now + Duration::from_secs(30);
+        assert!(breaker.allow_at(role, trial));
+        assert!(!breaker.allow_at(role, trial + Duration::from_secs(29)));
+
+        // another trial is let through once the cooldown passed again
+        let retrial = trial + Duration::from_secs(30);
+        assert!(breaker.allow_at(role, retrial));
+        assert!(!breaker.allow_at(role, retrial));
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::BTreeSet;
+use std::time::SystemTime;
+
+use aws_config::sts::AssumeRoleProvider;
+use aws_sdk_iam::error::DisplayErrorContext;
+use aws_smithy_types::date_time::Format;
+use aws_smithy_types::DateTime;
+use serde_json::json;
+use tracing::{error, info};
+
+use super::aws::AwsState;
+use crate::audit::{AuditEvent, AuditEventKind, Auditor, CacheResult, Outcome};
+use crate::error::Error;
+use crate::http::mappings::{Grant, Mapping};
+
+/// Name of the inline policy the IAM console attaches to revoke older sessions
+const REVOKE_POLICY_NAME: &str = "AWSRevokeOlderSessions";
+const REVOKE_SESSION_NAME: &str = "homelab-aws-creds-revoke-sessions";
+
+/// Revokes what a reload of the mappings unmapped. Cached credentials of every role a
+/// mapping lost are evicted, so they are not served to a service account that is mapped
+/// to the role later, and each lost role is audited. With a [`SessionRevoker`], the
+/// older sessions of roles no service account can get anymore are revoked as well.
+#[derive(Clone)]
+pub(crate) struct Revocation {
+    aws_state: AwsState,
+    auditor: Auditor,
+    sessions: Option<SessionRevoker>,
+}
+
+impl Revocation {
+    pub(crate) fn new(
+        aws_state: AwsState,
+        auditor: Auditor,
+        sessions: Option<SessionRevoker>,
+    ) -> Self {
+        Self {
+            aws_state,
+            auditor,
+            sessions,
+        }
+    }
+
+    /// Compares the roles granted before and after every reload of the mappings
+    pub(crate) async fn revoke_on_unmap(self, mapping: Mapping) {
+        let mut granted = mapping.grants();
+        let mut reloaded = mapping.subscribe();
+        while reloaded.changed().await.is_ok() {
+            let current = mapping.grants();
+            let removed: Vec<Grant> = granted.difference(&current).cloned().collect();
+            granted = current;
+            if !removed.is_empty() {
+                self.revoke(&granted, &removed).await;
+            }
+        }
+    }
+
+    async fn revoke(&self, granted: &BTreeSet<Grant>, removed: &[Grant]) {
+        let roles: BTreeSet<&str> = removed
+            .iter()
+            .map(|grant| grant.aws_role.as_str())
+            .collect();
+        for role in roles {
+            let evicted = self.aws_state.evict(role).await;
+            info!(
+                "role {} was unmapped, evicted cached credentials: {}",
+                role, evicted
+            );
+            // sessions of roles that are still granted are left alone, revoking them
+            // would break the service accounts that still use the role
+            let still_granted = granted.iter().any(|grant| grant.aws_role == role);
+            let revoked = match self.sessions {
+                Some(ref sessions) if !still_granted => Some(sessions.revoke(role).await),
+                _ => None,
+            };
+            for grant in removed.iter().filter(|grant| grant.aws_role == role) {
+                let mut event = AuditEvent::new(AuditEventKind::Revocation);
+                event.mapping = Some(grant.subject.clone());
+                event.role_arn = Some(grant.aws_role.clone());
+                event.cache = evicted.then_some(CacheResult::Evicted);
+                match revoked {
+                    Some(Ok(ref before)) => event.revoked_before = Some(before.clone()),
+                    Some(Err(_)) => {
+                        event.outcome = Outcome::Failure;
+                        event.error_code = Some("revoke_sessions_failed".into());
+                    }
+                    None => {}
+                }
+                self.auditor.emit(event);
+            }
+        }
+    }
+}
+
+/// Revokes the sessions of a role issued before now the way the IAM console does, with
+/// an inline policy denying everything to credentials issued before that time. Every
+/// agent revokes the sessions when it sees the reload, the last one wins.
+#[derive(Clone)]
+pub(crate) struct SessionRevoker {
+    iam_client: aws_sdk_iam::Client,
+}
+
+impl SessionRevoker {
+    /// Updates role policies with the credentials of the admin role
+    pub(crate) async fn new(admin_role: &str) -> Self {
+        let config = aws_config::load_from_env().await;
+        let provider = AssumeRoleProvider::builder(admin_role)
+            .session_name(REVOKE_SESSION_NAME)
+            .configure(&config)
+            .build()
+            .await;
+        let iam_config = aws_sdk_iam::config::Builder::from(&config)
+            .credentials_provider(provider)
+            .build();
+        Self {
+            iam_client: aws_sdk_iam::Client::from_conf(iam_config),
+        }
+    }
+
+    /// Revokes the older sessions of the role, returning the time they were issued before
+    async fn revoke(&self, role_arn: &str) -> Result<String, Error> {
+        let role_name = role_name(role_arn)
+            .ok_or_else(|| Error::AwsError(format!("{role_arn} is not a role ARN")))?;
+        let before = DateTime::from(SystemTime::now())
+            .fmt(Format::DateTime)
+            .map_err(|e| Error::AwsError(e.to_string()))?;
+        self.iam_client
+            .put_role_policy()
+            .role_name(role_name)
+            .policy_name(REVOKE_POLICY_NAME)
+            .policy_document(revoke_policy(&before))
+            .send()
+            .await
+            .map_err(|e| {
+                let e = Error::AwsError(DisplayErrorContext(&e).to_string());
+                error!(
+                    "failed to revoke older sessions of role {}: {}",
+                    role_arn, e
+                );
+                e
+            })?;
+        info!(
+            "revoked sessions of role {} issued before {}",
+            role_arn, before
+        );
+        Ok(before)
+    }
+}
+
+// Roles are addressed by name in IAM, which is the last segment of the ARN's path
+fn role_name(role_arn: &str) -> Option<&str> {
+    let (_, resource) = role_arn.rsplit_once(':')?;
+    resource.strip_prefix("role/")?.rsplit('/').next()
+}
+
+fn revoke_policy(before: &str) -> String {
+    json!({
+        "Version": "2012-10-17",
+        "Statement": [{
+            "Effect": "Deny",
+            "Action": ["*"],
+            "Resource": ["*"],
+            "Condition": {"DateLessThan": {"aws:TokenIssueTime": before}}
+        }]
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::audit::AuditEvent;
+    use crate::http::debug::DebugCache;
+
+    #[tokio::test]
+    async fn evicts_and_audits_unmapped_roles() {
+        let app = "arn:aws:iam::123456789012:role/app";
+        let worker = "arn:aws:iam::123456789012:role/worker";
+        let mapping = |role: &str, service_account: &str| {
+            format!(
+                "- namespace: default\n  serviceAccount: {service_account}\n  awsRole: {role}\n"
+            )
+        };
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("mappings.yaml");
+        tokio::fs::write(
+            &path,
+            format!(
+                "mappings:\n{}{}",
+                mapping(app, "app"),
+                mapping(worker, "worker")
+            ),
+        )
+        .await
+        .unwrap();
+        let mapping_file = Mapping::try_new_from_file(path.clone()).await.unwrap();
+        let aws_state = AwsState::unconfigured();
+        aws_state.cache_credential(app).await;
+        aws_state.cache_credential(worker).await;
+        let (auditor, mut events) = Auditor::channel();
+        let revocation = Revocation::new(aws_state.clone(), auditor, None);
+        tokio::spawn(revocation.revoke_on_unmap(mapping_file.clone()));
+        tokio::task::yield_now().await;
+
+        tokio::fs::write(&path, format!("mappings:\n{}", mapping(app, "app")))
+            .await
+            .unwrap();
+        mapping_file.reload().await.unwrap();
+        let event: AuditEvent = timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(event.kind, AuditEventKind::Revocation));
+        assert_eq!(event.mapping.as_deref(), Some("default/worker"));
+        assert_eq!(event.role_arn.as_deref(), Some(worker));
+        assert!(matches!(event.cache, Some(CacheResult::Evicted)));
+
+        let cached: Vec<String> = aws_state
+            .entries()
+            .await
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect();
+        assert_eq!(cached, [app]);
+    }
+
+    #[test]
+    fn revoke_policy_for_role() {
+        assert_eq!(
+            role_name("arn:aws:iam::123456789012:role/path/to/app"),
+            Some("app")
+        );
+        assert_eq!(role_name("arn:aws:iam::123456789012:user/app"), None);
+        let policy: serde_json::Value =
+            serde_json::from_str(&revoke_policy("2025-01-01T00:00:00Z")).unwrap();
+        assert_eq!(
+            policy["Statement"][0]["Condition"]["DateLessThan"]["aws:TokenIssueTime"],
+            "2025-01-01T00:00:00Z"
+        );
+    }
+}
@@ -1,42 +1,84 @@
-use super::aws::{AwsState, TemporaryCredential};
+use std::net::SocketAddr;
+
+use super::aws::{AwsState, CredentialSource, TemporaryCredential};
+use super::events::FailureEvents;
 use super::kubernetes::KubeState;
-use crate::error::Error;
+use super::negative_cache::{NegativeCache, NegativeKey};
+use crate::audit::{AuditEvent, AuditEventKind, Auditor, CacheResult, Outcome};
+use crate::config::ServerConfig;
+use crate::error::{Error, StsErrorKind};
+use crate::http::debug::RecentErrors;
 use crate::http::mappings::Mapping;
 use crate::http::middleware::add_default_middleware;
-use axum::extract::State;
+use axum::extract::{ConnectInfo, Path, State};
 use axum::response::IntoResponse;
 use axum::routing::get;
 use axum::{Json, Router};
-use http::{HeaderMap, StatusCode};
+use http::{Extensions, HeaderMap, StatusCode};
+use k8s_openapi::api::authentication::v1::UserInfo;
 use serde::Serialize;
 
 type NamespaceServiceAccount = (String, String);
 
+const EXTRA_POD_NAME: &str = "authentication.kubernetes.io/pod-name";
+const EXTRA_POD_UID: &str = "authentication.kubernetes.io/pod-uid";
+
+/// Identity of the pod making a credential request as returned by the TokenReview
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Identity {
+    pub namespace: String,
+    pub service_account: String,
+    pub pod_name: Option<String>,
+    pub pod_uid: Option<String>,
+}
+
+impl Identity {
+    fn try_from_user(user: UserInfo) -> Result<Self, Error> {
+        let username = user
+            .username
+            .ok_or_else(|| Error::TokenError("username not found in status".to_string()))?;
+        let (namespace, service_account) = get_namespace_sa(username)?;
+        let extra = |key: &str| {
+            user.extra
+                .as_ref()
+                .and_then(|extra| extra.get(key))
+                .and_then(|values| values.first())
+                .cloned()
+        };
+        Ok(Self {
+            namespace,
+            service_account,
+            pod_name: extra(EXTRA_POD_NAME),
+            pod_uid: extra(EXTRA_POD_UID),
+        })
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct AgentState {
-    aws_state: AwsState,
-    kube_state: KubeState,
-    role_mappings: Mapping,
+    pub(super) aws_state: AwsState,
+    pub(super) kube_state: KubeState,
+    pub(super) role_mappings: Mapping,
+    pub(super) negative_cache: NegativeCache,
+    pub(super) auditor: Auditor,
+    pub(super) failure_events: Option<FailureEvents>,
+    pub(super) recent_errors: RecentErrors,
+    pub(super) metrics_identity_labels: bool,
 }
 
 impl AgentState {
-    pub(crate) fn new(aws_state: AwsState, kube_state: KubeState, role_mappings: Mapping) -> Self {
-        Self {
-            aws_state,
-            kube_state,
-            role_mappings,
-        }
-    }
-
     async fn get_credentials(
         &self,
         role: String,
         session_name: String,
-    ) -> Result<TemporaryCredential, Error> {
-        self.aws_state.get_credentials(role, session_name).await
+        duration_seconds: Option<i32>,
+    ) -> Result<(TemporaryCredential, CredentialSource), Error> {
+        self.aws_state
+            .get_credentials(role, session_name, duration_seconds)
+            .await
     }
 
-    async fn check_token(&self, token: &str) -> Result<NamespaceServiceAccount, Error> {
+    async fn check_token(&self, token: &str) -> Result<Identity, Error> {
         let status = self.kube_state.allowed_token(token.into()).await?;
         match (&status.error, &status.authenticated) {
             (Some(e), _) => return Err(Error::TokenError(e.to_string())),
@@ -45,12 +87,10 @@ impl AgentState {
             }
             (_, _) => {}
         }
-        let username = status
+        let user = status
             .user
-            .ok_or_else(|| Error::TokenError("user not found".to_string()))?
-            .username
-            .ok_or_else(|| Error::TokenError("username not found in status".to_string()))?;
-        get_namespace_sa(username)
+            .ok_or_else(|| Error::TokenError("user not found".to_string()))?;
+        Identity::try_from_user(user)
     }
 }
 
@@ -58,6 +98,19 @@ impl AgentState {
 pub(crate) struct CredentialError {
     pub code: String,
     pub message: String,
+    /// Machine readable reason recorded in audit events
+    #[serde(skip)]
+    pub reason: &'static str,
+}
+
+impl CredentialError {
+    fn unauthorized(reason: &'static str, message: impl ToString) -> Self {
+        Self {
+            code: StatusCode::UNAUTHORIZED.to_string(),
+            message: message.to_string(),
+            reason,
+        }
+    }
 }
 
 //TODO: implement proper status code, currently returns 200
@@ -67,49 +120,186 @@ impl IntoResponse for CredentialError {
     }
 }
 
-pub(crate) fn new_agent_router(agent_state: AgentState) -> Router {
+pub(crate) fn new_agent_router(agent_state: AgentState, cfg: &ServerConfig) -> Router {
     let rt = Router::new()
         .route("/v1/container-credentials", get(container_credentials))
+        .route("/v1/container-credentials/{alias}", get(alias_credentials))
         .with_state(agent_state);
-    add_default_middleware(rt)
+    add_default_middleware(rt, cfg)
 }
 
 async fn container_credentials(
     State(state): State<AgentState>,
+    extensions: Extensions,
     headers: HeaderMap,
 ) -> Result<Json<TemporaryCredential>, CredentialError> {
-    let auth = headers
-        .get("authorization")
-        .ok_or_else(|| CredentialError {
-            code: StatusCode::UNAUTHORIZED.to_string(),
-            message: "No authorization token passed".to_string(),
-        })?;
-    let (namespace, sa) = state
-        .check_token(auth.to_str().map_err(|e| CredentialError {
-            code: StatusCode::UNAUTHORIZED.to_string(),
-            message: e.to_string(),
-        })?)
-        .await
-        .map_err(|e| CredentialError {
-            code: StatusCode::UNAUTHORIZED.to_string(),
-            message: e.to_string(),
-        })?;
-    let role = state
+    credentials(state, extensions, headers, None).await
+}
+
+/// Serves one of the named roles of the service account
+async fn alias_credentials(
+    State(state): State<AgentState>,
+    Path(alias): Path<String>,
+    extensions: Extensions,
+    headers: HeaderMap,
+) -> Result<Json<TemporaryCredential>, CredentialError> {
+    credentials(state, extensions, headers, Some(alias)).await
+}
+
+async fn credentials(
+    state: AgentState,
+    extensions: Extensions,
+    headers: HeaderMap,
+    alias: Option<String>,
+) -> Result<Json<TemporaryCredential>, CredentialError> {
+    let mut event = AuditEvent::new(AuditEventKind::CredentialRequest);
+    event.peer_ip = extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+    event.role_alias = alias.clone();
+    let result = issue_credentials(&state, &headers, alias.as_deref(), &mut event).await;
+    if let Err(ref e) = result {
+        event.outcome = Outcome::Failure;
+        event.error_code = Some(e.reason.to_string());
+        if let Some(ref failure_events) = state.failure_events {
+            failure_events.publish(&event, e.reason, e.message.clone());
+        }
+    }
+    record_request_metrics(&state, &event);
+    state.auditor.emit(event);
+    result.map(Json)
+}
+
+async fn issue_credentials(
+    state: &AgentState,
+    headers: &HeaderMap,
+    alias: Option<&str>,
+    event: &mut AuditEvent,
+) -> Result<TemporaryCredential, CredentialError> {
+    let auth = headers.get("authorization").ok_or_else(|| {
+        CredentialError::unauthorized("missing_token", "No authorization token passed")
+    })?;
+    let token = auth
+        .to_str()
+        .map_err(|e| CredentialError::unauthorized("invalid_token", e))?;
+    let token_key = NegativeKey::token(token, alias);
+    if let Some(e) = state.negative_cache.get(&token_key) {
+        event.cache = Some(CacheResult::Negative);
+        return Err(e);
+    }
+    let identity = state.check_token(token).await.map_err(|e| {
+        state.recent_errors.record("token_review", &e);
+        // only cache definitive rejections, not failures reaching the API server
+        if matches!(e, Error::TokenError(_)) {
+            let err = CredentialError::unauthorized("invalid_token", &e);
+            state.negative_cache.insert(token_key.clone(), err.clone());
+            err
+        } else {
+            CredentialError::unauthorized("token_review_failed", &e)
+        }
+    })?;
+    let Identity {
+        namespace,
+        service_account: sa,
+        pod_name,
+        pod_uid,
+    } = identity;
+    event.namespace = Some(namespace.clone());
+    event.service_account = Some(sa.clone());
+    event.pod_name = pod_name;
+    event.pod_uid = pod_uid;
+
+    let Some(mapping) = state
         .role_mappings
         .get_role(namespace.as_str(), sa.as_str())
-        .ok_or(CredentialError {
-            code: StatusCode::UNAUTHORIZED.to_string(),
-            message: "role not found serviceaccount mappings".to_string(),
+    else {
+        let err = CredentialError::unauthorized(
+            "unmapped_service_account",
+            "role not found serviceaccount mappings",
+        );
+        state.negative_cache.insert(token_key, err.clone());
+        return Err(err);
+    };
+    // the alias has to be one of the service account's roles, which is what binds it
+    // to the service account
+    let Some(selected) = mapping.role(alias) else {
+        let err = CredentialError::unauthorized(
+            "unmapped_role_alias",
+            format!(
+                "role {} not found in serviceaccount mappings",
+                alias.unwrap_or_default()
+            ),
+        );
+        state.negative_cache.insert(token_key, err.clone());
+        return Err(err);
+    };
+    let role = selected.aws_role.to_string();
+    let session_name = selected
+        .session_name
+        .map(String::from)
+        .unwrap_or_else(|| format!("{namespace}-{sa}"));
+    let duration_seconds = selected.duration_seconds;
+    event.role_arn = Some(role.clone());
+    event.session_name = Some(session_name.clone());
+
+    let role_key = NegativeKey::Role(role.clone());
+    if let Some(e) = state.negative_cache.get(&role_key) {
+        event.cache = Some(CacheResult::Negative);
+        return Err(e);
+    }
+    let (creds, source) = state
+        .get_credentials(role, session_name, duration_seconds)
+        .await
+        .inspect_err(|e| state.recent_errors.record("sts", e))
+        .map_err(|e| match e {
+            Error::AssumeRoleError { kind, .. } => {
+                let err = CredentialError::unauthorized(sts_error_reason(kind), &e);
+                if matches!(
+                    kind,
+                    StsErrorKind::AccessDenied | StsErrorKind::MalformedPolicy
+                ) {
+                    state.negative_cache.insert(role_key, err.clone());
+                    state.negative_cache.insert(token_key, err.clone());
+                }
+                err
+            }
+            Error::CircuitOpen(_) => CredentialError::unauthorized("circuit_open", &e),
+            _ => CredentialError::unauthorized("sts_error", &e),
         })?;
-    Ok(Json(
-        state
-            .get_credentials(role, format!("{namespace}-{sa}"))
-            .await
-            .map_err(|e| CredentialError {
-                code: StatusCode::UNAUTHORIZED.to_string(),
-                message: e.to_string(),
-            })?,
-    ))
+    event.cache = Some(match source {
+        CredentialSource::Cache => CacheResult::Hit,
+        CredentialSource::Sts => CacheResult::Miss,
+        CredentialSource::StaleCache => CacheResult::Stale,
+    });
+    Ok(creds)
+}
+
+fn record_request_metrics(state: &AgentState, event: &AuditEvent) {
+    let outcome = match event.outcome {
+        Outcome::Success => "success",
+        Outcome::Failure => "failure",
+    };
+    if state.metrics_identity_labels {
+        metrics::counter!(
+            "credential_requests",
+            "outcome" => outcome,
+            "namespace" => event.namespace.clone().unwrap_or_default(),
+            "service_account" => event.service_account.clone().unwrap_or_default()
+        )
+        .increment(1);
+    } else {
+        metrics::counter!("credential_requests", "outcome" => outcome).increment(1);
+    }
+}
+
+fn sts_error_reason(kind: StsErrorKind) -> &'static str {
+    match kind {
+        StsErrorKind::Throttling => "sts_throttling",
+        StsErrorKind::Transient => "sts_transient",
+        StsErrorKind::AccessDenied => "sts_access_denied",
+        StsErrorKind::MalformedPolicy => "sts_malformed_policy",
+        StsErrorKind::Other => "sts_error",
+    }
 }
 
 fn get_namespace_sa(username: String) -> Result<NamespaceServiceAccount, Error> {
@@ -124,6 +314,8 @@ fn get_namespace_sa(username: String) -> Result<NamespaceServiceAccount, Error>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::BTreeMap;
+
     #[test]
     fn username_parse() {
         assert_eq!(
@@ -131,4 +323,25 @@ mod tests {
             ("default".into(), "test".into())
         );
     }
+
+    #[test]
+    fn identity_from_user() {
+        let user = UserInfo {
+            username: Some("system:serviceaccount:default:test".into()),
+            extra: Some(BTreeMap::from([
+                (EXTRA_POD_NAME.to_string(), vec!["test-pod".to_string()]),
+                (EXTRA_POD_UID.to_string(), vec!["1234".to_string()]),
+            ])),
+            ..Default::default()
+        };
+        assert_eq!(
+            Identity::try_from_user(user).unwrap(),
+            Identity {
+                namespace: "default".into(),
+                service_account: "test".into(),
+                pod_name: Some("test-pod".into()),
+                pod_uid: Some("1234".into()),
+            }
+        );
+    }
 }
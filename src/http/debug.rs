@@ -0,0 +1,335 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use aws_smithy_types::date_time::Format;
+use aws_smithy_types::DateTime;
+use axum::extract::{Request, State};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures_util::future::BoxFuture;
+use http::StatusCode;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use super::mappings::{AnnotationPolicy, Mapping, Provenance, ServiceRoleMapping};
+
+const RECENT_ERRORS: usize = 50;
+
+/// A cache whose entries are listed on the debug routes and that can be flushed.
+/// Implementations must never expose credentials or tokens.
+pub(crate) trait DebugCache: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn entries(&self) -> BoxFuture<'_, Vec<CacheEntry>>;
+    fn flush(&self) -> BoxFuture<'_, ()>;
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CacheEntry {
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub expires_in_seconds: i64,
+}
+
+/// Internal state shown on the debug routes of the metrics listener. Components
+/// register themselves as they are created.
+#[derive(Clone)]
+pub(crate) struct DebugState {
+    mappings: Arc<RwLock<Option<Mapping>>>,
+    caches: Arc<RwLock<Vec<Arc<dyn DebugCache>>>>,
+    errors: RecentErrors,
+    started_at: SystemTime,
+}
+
+impl Default for DebugState {
+    fn default() -> Self {
+        Self {
+            mappings: Default::default(),
+            caches: Default::default(),
+            errors: Default::default(),
+            started_at: SystemTime::now(),
+        }
+    }
+}
+
+impl DebugState {
+    pub(crate) fn set_mappings(&self, mappings: Mapping) {
+        *self.mappings.write().expect("debug state lock poisoned") = Some(mappings);
+    }
+
+    pub(crate) fn register_cache(&self, cache: impl DebugCache + 'static) {
+        self.caches
+            .write()
+            .expect("debug state lock poisoned")
+            .push(Arc::new(cache));
+    }
+
+    pub(crate) fn recent_errors(&self) -> RecentErrors {
+        self.errors.clone()
+    }
+
+    fn mappings(&self) -> Option<Mapping> {
+        self.mappings
+            .read()
+            .expect("debug state lock poisoned")
+            .clone()
+    }
+
+    fn caches(&self) -> Vec<Arc<dyn DebugCache>> {
+        self.caches
+            .read()
+            .expect("debug state lock poisoned")
+            .clone()
+    }
+}
+
+/// Ring buffer of the most recent TokenReview and STS errors
+#[derive(Clone, Default)]
+pub(crate) struct RecentErrors {
+    entries: Arc<Mutex<VecDeque<RecordedError>>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct RecordedError {
+    timestamp: String,
+    source: &'static str,
+    message: String,
+}
+
+impl RecentErrors {
+    pub(crate) fn record(&self, source: &'static str, message: impl ToString) {
+        let mut entries = self.entries.lock().expect("recent errors lock poisoned");
+        if entries.len() == RECENT_ERRORS {
+            entries.pop_front();
+        }
+        entries.push_back(RecordedError {
+            timestamp: format_time(SystemTime::now()),
+            source,
+            message: message.to_string(),
+        });
+    }
+
+    fn snapshot(&self) -> Vec<RecordedError> {
+        self.entries
+            .lock()
+            .expect("recent errors lock poisoned")
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+}
+
+/// Debug routes requiring the bearer token stored in `token_file`. The file is read
+/// on every request so the token can be rotated without a restart.
+pub(crate) fn debug_router(state: DebugState, token_file: PathBuf) -> Router {
+    Router::new()
+        .route("/debug/mappings", get(mappings))
+        .route("/debug/cache", get(cache))
+        .route("/debug/errors", get(errors))
+        .route("/debug/version", get(version))
+        .route("/debug/flush", post(flush))
+        .route("/debug/reload", post(reload))
+        .with_state(state)
+        .layer(middleware::from_fn_with_state(
+            Arc::new(token_file),
+            require_token,
+        ))
+}
+
+async fn require_token(
+    State(token_file): State<Arc<PathBuf>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let expected = match tokio::fs::read_to_string(token_file.as_path()).await {
+        Ok(token) => token.trim().to_string(),
+        Err(e) => {
+            warn!("failed to read debug token file: {}", e);
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    };
+    let provided = request
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // compare digests so the comparison time does not depend on the token contents
+    match provided {
+        Some(provided)
+            if !expected.is_empty()
+                && Sha256::digest(provided.as_bytes()) == Sha256::digest(expected.as_bytes()) =>
+        {
+            next.run(request).await
+        }
+        _ => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MappingsInfo {
+    path: PathBuf,
+    hash: String,
+    loaded_at: String,
+    mappings: Vec<MappingInfo>,
+    annotation_policies: Vec<AnnotationPolicy>,
+    role_bindings: Vec<ServiceRoleMapping>,
+}
+
+#[derive(Serialize)]
+struct MappingInfo {
+    #[serde(flatten)]
+    mapping: ServiceRoleMapping,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<Provenance>,
+}
+
+async fn mappings(State(state): State<DebugState>) -> Result<Json<MappingsInfo>, StatusCode> {
+    let mapping = state.mappings().ok_or(StatusCode::NOT_FOUND)?;
+    let loaded = mapping.mappings.load();
+    Ok(Json(MappingsInfo {
+        path: mapping.path().to_path_buf(),
+        hash: loaded.hash.clone(),
+        loaded_at: format_time(loaded.loaded_at),
+        mappings: loaded
+            .mappings
+            .iter()
+            .enumerate()
+            .map(|(i, mapping)| MappingInfo {
+                mapping: mapping.clone(),
+                source: loaded.provenance.get(i).cloned(),
+            })
+            .collect(),
+        annotation_policies: loaded.annotation_policies.clone(),
+        role_bindings: mapping.role_bindings().mappings.clone(),
+    }))
+}
+
+#[derive(Serialize)]
+struct CacheInfo {
+    name: &'static str,
+    entries: Vec<CacheEntry>,
+}
+
+async fn cache(State(state): State<DebugState>) -> Json<Vec<CacheInfo>> {
+    let mut caches = vec![];
+    for cache in state.caches() {
+        caches.push(CacheInfo {
+            name: cache.name(),
+            entries: cache.entries().await,
+        });
+    }
+    Json(caches)
+}
+
+async fn errors(State(state): State<DebugState>) -> Json<Vec<RecordedError>> {
+    Json(state.errors.snapshot())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VersionInfo {
+    name: &'static str,
+    version: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    git_commit: Option<&'static str>,
+    target: String,
+    started_at: String,
+    uptime_seconds: u64,
+}
+
+async fn version(State(state): State<DebugState>) -> Json<VersionInfo> {
+    Json(VersionInfo {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: option_env!("GIT_COMMIT").filter(|commit| !commit.is_empty()),
+        target: format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+        started_at: format_time(state.started_at),
+        uptime_seconds: state
+            .started_at
+            .elapsed()
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    })
+}
+
+async fn flush(State(state): State<DebugState>) -> StatusCode {
+    for cache in state.caches() {
+        info!("flushing {} cache from debug endpoint", cache.name());
+        cache.flush().await;
+    }
+    StatusCode::NO_CONTENT
+}
+
+async fn reload(State(state): State<DebugState>) -> Result<StatusCode, (StatusCode, String)> {
+    let mapping = state
+        .mappings()
+        .ok_or((StatusCode::NOT_FOUND, "no mappings loaded".to_string()))?;
+    info!("reloading mappings from debug endpoint");
+    mapping
+        .reload()
+        .await
+        .map(|_| StatusCode::NO_CONTENT)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+fn format_time(time: SystemTime) -> String {
+    DateTime::from(time)
+        .fmt(Format::DateTime)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    #[test]
+    fn recent_errors_are_bounded() {
+        let errors = RecentErrors::default();
+        for i in 0..RECENT_ERRORS + 5 {
+            errors.record("sts", format!("error {i}"));
+        }
+        let snapshot = errors.snapshot();
+        assert_eq!(snapshot.len(), RECENT_ERRORS);
+        assert_eq!(snapshot[0].message, format!("error {}", RECENT_ERRORS + 4));
+        assert_eq!(snapshot[RECENT_ERRORS - 1].message, "error 5");
+    }
+
+    #[tokio::test]
+    async fn requires_token() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let token_file = dir.join("token");
+        tokio::fs::write(&token_file, "secret\n").await.unwrap();
+        let router = debug_router(DebugState::default(), token_file);
+
+        let status = |auth: Option<&'static str>| {
+            let router = router.clone();
+            async move {
+                let mut request = Request::get("/debug/errors");
+                if let Some(auth) = auth {
+                    request = request.header("authorization", auth);
+                }
+                router
+                    .oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+        assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("Bearer wrong")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("Bearer secret")).await, StatusCode::OK);
+    }
+}
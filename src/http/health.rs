@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tracing::warn;
+
+use crate::error::Error;
+
+/// Result of a single readiness check
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub(crate) struct CheckStatus {
+    pub ready: bool,
+    pub message: String,
+}
+
+impl CheckStatus {
+    pub(crate) fn ready(message: impl Into<String>) -> Self {
+        Self {
+            ready: true,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn not_ready(message: impl Into<String>) -> Self {
+        Self {
+            ready: false,
+            message: message.into(),
+        }
+    }
+}
+
+/// A dependency the process needs to serve requests. Checks must be cheap as they are
+/// evaluated on every readiness probe; anything slow should be refreshed in the
+/// background, see [`HealthChecks::spawn_periodic`].
+pub(crate) trait ReadinessCheck: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn status(&self) -> CheckStatus;
+}
+
+/// Registry of readiness checks shared between the servers and the metrics listener.
+/// The metrics listener is up before the servers have registered their checks, so the
+/// registry is not ready until [`HealthChecks::initialised`] is called.
+#[derive(Clone, Default)]
+pub(crate) struct HealthChecks {
+    checks: Arc<RwLock<Vec<Arc<dyn ReadinessCheck>>>>,
+    initialised: Arc<AtomicBool>,
+}
+
+impl HealthChecks {
+    /// Marks every check of the server as registered
+    pub(crate) fn initialised(&self) {
+        self.initialised.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn register(&self, check: impl ReadinessCheck + 'static) {
+        self.checks
+            .write()
+            .expect("health checks lock poisoned")
+            .push(Arc::new(check));
+    }
+
+    pub(crate) fn report(&self) -> BTreeMap<&'static str, CheckStatus> {
+        let mut report: BTreeMap<_, _> = self
+            .checks
+            .read()
+            .expect("health checks lock poisoned")
+            .iter()
+            .map(|check| (check.name(), check.status()))
+            .collect();
+        let startup = if self.initialised.load(Ordering::Relaxed) {
+            CheckStatus::ready("started")
+        } else {
+            CheckStatus::not_ready("starting")
+        };
+        report.insert("startup", startup);
+        report
+    }
+
+    /// Registers a check whose status is refreshed by running `check` every `interval`
+    pub(crate) fn spawn_periodic<F, Fut>(&self, name: &'static str, interval: Duration, check: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<String, Error>> + Send,
+    {
+        let status = StatusCheck::new(name);
+        self.register(status.clone());
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match check().await {
+                    Ok(message) => status.set(CheckStatus::ready(message)),
+                    Err(e) => {
+                        warn!("readiness check {} failed: {}", name, e);
+                        status.set(CheckStatus::not_ready(e.to_string()))
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Readiness check holding the last status reported by its owner
+#[derive(Clone)]
+pub(crate) struct StatusCheck {
+    name: &'static str,
+    status: Arc<RwLock<CheckStatus>>,
+}
+
+impl StatusCheck {
+    pub(crate) fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            status: Arc::new(RwLock::new(CheckStatus::not_ready("not checked yet"))),
+        }
+    }
+
+    pub(crate) fn set(&self, status: CheckStatus) {
+        *self.status.write().expect("status check lock poisoned") = status;
+    }
+}
+
+impl ReadinessCheck for StatusCheck {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn status(&self) -> CheckStatus {
+        self.status
+            .read()
+            .expect("status check lock poisoned")
+            .clone()
+    }
+}
+
+/// Detects a wedged runtime by tracking when a background task last got to run
+#[derive(Clone)]
+pub(crate) struct Heartbeat {
+    last_beat: Arc<AtomicU64>,
+    timeout: Duration,
+}
+
+impl Heartbeat {
+    pub(crate) fn start(timeout: Duration) -> Self {
+        let heartbeat = Self {
+            last_beat: Arc::new(AtomicU64::new(now_secs())),
+            timeout,
+        };
+        let last_beat = heartbeat.last_beat.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                ticker.tick().await;
+                last_beat.store(now_secs(), Ordering::Relaxed);
+            }
+        });
+        heartbeat
+    }
+
+    pub(crate) fn alive(&self) -> bool {
+        now_secs().saturating_sub(self.last_beat.load(Ordering::Relaxed)) <= self.timeout.as_secs()
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_contains_registered_checks() {
+        let checks = HealthChecks::default();
+        assert_eq!(
+            checks.report().get("startup"),
+            Some(&CheckStatus::not_ready("starting"))
+        );
+        let mappings = StatusCheck::new("mappings");
+        checks.register(mappings.clone());
+        checks.initialised();
+        assert!(checks.report()["startup"].ready);
+        assert_eq!(
+            checks.report().get("mappings"),
+            Some(&CheckStatus::not_ready("not checked yet"))
+        );
+
+        mappings.set(CheckStatus::ready("loaded"));
+        assert_eq!(
+            checks.report().get("mappings"),
+            Some(&CheckStatus::ready("loaded"))
+        );
+    }
+
+    #[tokio::test]
+    async fn heartbeat_alive() {
+        let heartbeat = Heartbeat::start(Duration::from_secs(5));
+        assert!(heartbeat.alive());
+        heartbeat.last_beat.store(0, Ordering::Relaxed);
+        assert!(!heartbeat.alive());
+    }
+}
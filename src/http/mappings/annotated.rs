@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use futures_util::StreamExt;
+use k8s_openapi::api::core::v1::ServiceAccount;
+use kube::core::PartialObjectMeta;
+use kube::runtime::{metadata_watcher, watcher, WatchStreamExt};
+use kube::{Api, Client as KubeClient, ResourceExt};
+use tokio::sync::watch;
+use tracing::warn;
+
+use super::ServiceRoleMapping;
+use crate::http::health::{CheckStatus, StatusCheck};
+
+/// IRSA annotation declaring the role of a service account
+const ROLE_ANNOTATION: &str = "eks.amazonaws.com/role-arn";
+
+// mappings by namespace and then service account
+type Roles = HashMap<String, HashMap<String, Arc<ServiceRoleMapping>>>;
+
+/// Roles annotated on service accounts, compiled into mappings when the service accounts
+/// change so looking them up does not allocate. Whether an annotation policy allows a
+/// role is checked when it is looked up, as the policies are reloaded with the file.
+#[derive(Debug, Default)]
+pub(super) struct AnnotatedRoles {
+    roles: RwLock<Roles>,
+}
+
+impl AnnotatedRoles {
+    /// Keeps the roles in sync with the service accounts in the cluster, notifying
+    /// `changed` when they change. Returns a readiness check reporting whether they have
+    /// been listed.
+    pub(super) fn watch(
+        self: &Arc<Self>,
+        client: KubeClient,
+        changed: Arc<watch::Sender<()>>,
+    ) -> StatusCheck {
+        let ready = StatusCheck::new("service_accounts");
+        tokio::spawn(sync_service_accounts(
+            client,
+            self.clone(),
+            changed,
+            ready.clone(),
+        ));
+        ready
+    }
+
+    /// Mapping of the role annotated on the service account
+    pub(super) fn get(
+        &self,
+        namespace: &str,
+        service_account: &str,
+    ) -> Option<Arc<ServiceRoleMapping>> {
+        self.roles
+            .read()
+            .expect("annotated roles lock poisoned")
+            .get(namespace)?
+            .get(service_account)
+            .cloned()
+    }
+
+    /// Mappings of every annotated role
+    pub(super) fn mappings(&self) -> Vec<Arc<ServiceRoleMapping>> {
+        self.roles
+            .read()
+            .expect("annotated roles lock poisoned")
+            .values()
+            .flat_map(HashMap::values)
+            .cloned()
+            .collect()
+    }
+
+    // returns whether the role of the service account changed
+    fn apply(&self, account: &PartialObjectMeta<ServiceAccount>) -> bool {
+        apply(
+            &mut self.roles.write().expect("annotated roles lock poisoned"),
+            account,
+        )
+    }
+
+    // returns whether the service account had a role
+    fn delete(&self, account: &PartialObjectMeta<ServiceAccount>) -> bool {
+        let mut roles = self.roles.write().expect("annotated roles lock poisoned");
+        account
+            .namespace()
+            .is_some_and(|namespace| remove(&mut roles, &namespace, &account.name_any()))
+    }
+
+    fn replace(&self, roles: Roles) {
+        *self.roles.write().expect("annotated roles lock poisoned") = roles;
+    }
+}
+
+async fn sync_service_accounts(
+    client: KubeClient,
+    roles: Arc<AnnotatedRoles>,
+    changed: Arc<watch::Sender<()>>,
+    ready: StatusCheck,
+) {
+    let api: Api<ServiceAccount> = Api::all(client);
+    let mut events = metadata_watcher(api, watcher::Config::default())
+        .default_backoff()
+        .boxed();
+    // service accounts are collected while they are relisted and then replace the roles,
+    // so the roles of deleted ones are dropped
+    let mut relisted = Roles::new();
+    while let Some(event) = events.next().await {
+        match event {
+            Ok(watcher::Event::Init) => relisted.clear(),
+            Ok(watcher::Event::InitApply(account)) => {
+                apply(&mut relisted, &account);
+            }
+            Ok(watcher::Event::InitDone) => {
+                roles.replace(std::mem::take(&mut relisted));
+                ready.set(CheckStatus::ready("service_accounts listed"));
+                changed.send_replace(());
+            }
+            Ok(watcher::Event::Apply(account)) => {
+                if roles.apply(&account) {
+                    changed.send_replace(());
+                }
+            }
+            Ok(watcher::Event::Delete(account)) => {
+                if roles.delete(&account) {
+                    changed.send_replace(());
+                }
+            }
+            Err(e) => warn!("failed to watch service_accounts: {}", e),
+        }
+    }
+}
+
+// returns whether the role of the service account changed
+fn apply(roles: &mut Roles, account: &PartialObjectMeta<ServiceAccount>) -> bool {
+    let Some(namespace) = account.namespace() else {
+        return false;
+    };
+    let name = account.name_any();
+    let Some(role) = account.annotations().get(ROLE_ANNOTATION) else {
+        return remove(roles, &namespace, &name);
+    };
+    let current = roles
+        .get(&namespace)
+        .and_then(|accounts| accounts.get(&name))
+        .and_then(|mapping| mapping.aws_role.as_ref());
+    if current == Some(role) {
+        return false;
+    }
+    let mapping = ServiceRoleMapping {
+        service_account: name.clone(),
+        namespace: Some(namespace.clone()),
+        namespace_regex: None,
+        namespace_selector: None,
+        aws_role: Some(role.clone()),
+        roles: vec![],
+        session_name: None,
+        duration_seconds: None,
+    };
+    roles
+        .entry(namespace)
+        .or_default()
+        .insert(name, Arc::new(mapping));
+    true
+}
+
+// returns whether the service account had a role
+fn remove(roles: &mut Roles, namespace: &str, name: &str) -> bool {
+    let Some(accounts) = roles.get_mut(namespace) else {
+        return false;
+    };
+    let removed = accounts.remove(name).is_some();
+    if accounts.is_empty() {
+        roles.remove(namespace);
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use kube::api::ObjectMeta;
+
+    use super::*;
+
+    fn account(name: &str, role: Option<&str>) -> PartialObjectMeta<ServiceAccount> {
+        PartialObjectMeta {
+            types: None,
+            metadata: ObjectMeta {
+                name: Some(name.into()),
+                namespace: Some("default".into()),
+                annotations: role
+                    .map(|role| BTreeMap::from([(ROLE_ANNOTATION.to_string(), role.to_string())])),
+                ..Default::default()
+            },
+            _phantom: Default::default(),
+        }
+    }
+
+    #[test]
+    fn compiles_annotated_roles() {
+        let roles = AnnotatedRoles::default();
+        assert!(roles.apply(&account("app", Some("arn:aws:iam::123456789012:role/app"))));
+        assert!(!roles.apply(&account("app", Some("arn:aws:iam::123456789012:role/app"))));
+        assert!(!roles.apply(&account("other", None)));
+
+        let mapping = roles.get("default", "app").unwrap();
+        assert_eq!(
+            mapping.aws_role.as_deref(),
+            Some("arn:aws:iam::123456789012:role/app")
+        );
+        // lookups share the mapping compiled when the service account changed
+        assert!(Arc::ptr_eq(&mapping, &roles.get("default", "app").unwrap()));
+        assert!(roles.get("default", "other").is_none());
+
+        assert!(roles.apply(&account("app", None)));
+        assert!(roles.get("default", "app").is_none());
+        roles.apply(&account("app", Some("arn:aws:iam::123456789012:role/app")));
+        assert!(roles.delete(&account("app", None)));
+        assert!(roles.mappings().is_empty());
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use aws_smithy_types::date_time::Format;
+use aws_smithy_types::DateTime;
+use futures_util::StreamExt;
+use k8s_openapi::api::core::v1::Namespace;
+use kube::api::{Patch, PatchParams};
+use kube::core::PartialObjectMeta;
+use kube::runtime::{reflector, watcher, WatchStreamExt};
+use kube::{Api, Client as KubeClient, ResourceExt};
+use serde_json::json;
+use tracing::{error, info, warn};
+
+use super::{annotation_allowed, validate, Mapping, Mappings, ServiceRoleMapping};
+use crate::crd::{AWSRoleBinding, BindingCondition};
+use crate::http::health::{CheckStatus, StatusCheck};
+
+const CONDITION_READY: &str = "Ready";
+
+impl Mapping {
+    /// Merges AWSRoleBinding resources into the mappings, keeping them in sync with the
+    /// cluster. With `write_status` the Ready condition of every binding is updated,
+    /// which only one component should do. Returns a readiness check reporting whether
+    /// the bindings have been listed.
+    pub(super) fn watch_role_bindings(
+        &self,
+        client: KubeClient,
+        write_status: bool,
+    ) -> StatusCheck {
+        let ready = StatusCheck::new("role_bindings");
+        tokio::spawn(sync_role_bindings(
+            client,
+            self.clone(),
+            write_status,
+            ready.clone(),
+        ));
+        ready
+    }
+}
+
+async fn sync_role_bindings(
+    client: KubeClient,
+    mapping: Mapping,
+    write_status: bool,
+    ready: StatusCheck,
+) {
+    let api: Api<AWSRoleBinding> = Api::all(client.clone());
+    let (reader, writer) = reflector::store();
+    let mut events = watcher(api, watcher::Config::default())
+        .default_backoff()
+        .reflect(writer)
+        .boxed();
+    let mut reloaded = mapping.subscribe();
+    let mut synced = false;
+    let mut file_hash = mapping.mappings.load().hash.clone();
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(Ok(watcher::Event::Apply(_) | watcher::Event::Delete(_))) if synced => {}
+                Some(Ok(watcher::Event::InitDone)) => synced = true,
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    warn!("failed to watch role bindings: {}", e);
+                    continue;
+                }
+                None => return,
+            },
+            // conflicts with the file are re-evaluated when it changes. Storing the
+            // bindings notifies as well, which is ignored as the file is unchanged
+            Ok(()) = reloaded.changed(), if synced => {
+                let hash = mapping.mappings.load().hash.clone();
+                if hash == file_hash {
+                    continue;
+                }
+                file_hash = hash;
+            }
+        }
+        let bindings = reader.state();
+        let resolved = resolve_bindings(&bindings, &mapping.mappings.load(), |namespace| {
+            mapping.namespace_meta(namespace)
+        });
+        let active: Vec<_> = resolved
+            .iter()
+            .filter_map(|(_, result)| result.as_ref().ok().cloned())
+            .collect();
+        let message = format!(
+            "{} of {} role bindings active",
+            active.len(),
+            bindings.len()
+        );
+        match Mappings::try_new(active) {
+            Ok(active) => {
+                info!("{}", message);
+                ready.set(CheckStatus::ready(message));
+                mapping.store_bindings(active);
+            }
+            // bindings are validated when resolving them so this is not expected
+            Err(e) => error!("failed to compile role bindings: {}", e),
+        }
+        if write_status {
+            for (binding, result) in &resolved {
+                update_status(&client, binding, result).await;
+            }
+        }
+    }
+}
+
+/// Reason and message of a binding that is not active
+type Inactive = (&'static str, String);
+
+// Bindings are applied oldest first so a new binding cannot take over a service
+// account from an existing one. The mapping file always wins, including its mappings
+// with patterns and namespace selectors, and its annotation policies limit the roles
+// bindings may claim just like annotations.
+fn resolve_bindings(
+    bindings: &[Arc<AWSRoleBinding>],
+    file: &Mappings,
+    namespace_meta: impl Fn(&str) -> Option<Arc<PartialObjectMeta<Namespace>>>,
+) -> Vec<(Arc<AWSRoleBinding>, Result<ServiceRoleMapping, Inactive>)> {
+    let mut bindings = bindings.to_vec();
+    bindings.sort_by(|a, b| {
+        (a.creation_timestamp(), a.namespace(), a.name_any()).cmp(&(
+            b.creation_timestamp(),
+            b.namespace(),
+            b.name_any(),
+        ))
+    });
+    let mut bound: HashMap<(String, String), String> = HashMap::new();
+    bindings
+        .into_iter()
+        .map(|binding| {
+            let result = resolve_binding(&binding, file, &namespace_meta, &mut bound);
+            (binding, result)
+        })
+        .collect()
+}
+
+fn resolve_binding(
+    binding: &AWSRoleBinding,
+    file: &Mappings,
+    namespace_meta: impl Fn(&str) -> Option<Arc<PartialObjectMeta<Namespace>>>,
+    bound: &mut HashMap<(String, String), String>,
+) -> Result<ServiceRoleMapping, Inactive> {
+    let namespace = binding.namespace().unwrap_or_default();
+    let spec = &binding.spec;
+    validate(binding).map_err(|message| ("Invalid", message))?;
+    let meta = namespace_meta(&namespace);
+    let labels = || meta.as_ref()?.metadata.labels.as_ref();
+    if !annotation_allowed(file, &namespace, &spec.aws_role, labels) {
+        return Err((
+            "NotAllowed",
+            format!(
+                "role {} is not allowed in the namespace by any annotation policy",
+                spec.aws_role
+            ),
+        ));
+    }
+    if let Some(mapped) = file.index.lookup(&namespace, &spec.service_account, labels) {
+        let provenance = file
+            .mappings
+            .iter()
+            .position(|m| m == mapped.as_ref())
+            .and_then(|i| file.provenance.get(i));
+        let source = match provenance {
+            Some(provenance) => format!(" {}", provenance.file.display()),
+            None => String::new(),
+        };
+        return Err((
+            "Conflict",
+            format!("service account is already mapped in the mapping file{source}"),
+        ));
+    }
+    let key = (namespace.clone(), spec.service_account.clone());
+    if let Some(other) = bound.get(&key) {
+        return Err((
+            "Conflict",
+            format!("service account is already bound by AWSRoleBinding {other}"),
+        ));
+    }
+    bound.insert(key, binding.name_any());
+    Ok(ServiceRoleMapping {
+        service_account: spec.service_account.clone(),
+        namespace: Some(namespace),
+        namespace_regex: None,
+        namespace_selector: None,
+        aws_role: Some(spec.aws_role.clone()),
+        roles: vec![],
+        session_name: spec.session_name.clone(),
+        duration_seconds: spec.duration_seconds,
+    })
+}
+
+// Uses the same rules as the mapping file so active bindings always compile
+fn validate(binding: &AWSRoleBinding) -> Result<(), String> {
+    let spec = &binding.spec;
+    validate::service_account_name(&spec.service_account)?;
+    validate::role_arn(&spec.aws_role)?;
+    if let Some(ref session_name) = spec.session_name {
+        validate::session_name(session_name)?;
+    }
+    if let Some(duration) = spec.duration_seconds {
+        validate::duration_seconds(duration)?;
+    }
+    Ok(())
+}
+
+// Only writes the status when the condition changed, so the update does not cause
+// another round of updates when it is observed by the watch
+async fn update_status(
+    client: &KubeClient,
+    binding: &AWSRoleBinding,
+    result: &Result<ServiceRoleMapping, Inactive>,
+) {
+    let (status, reason, message) = match result {
+        Ok(_) => (
+            "True",
+            "Bound",
+            format!("service account is bound to {}", binding.spec.aws_role),
+        ),
+        Err((reason, message)) => ("False", *reason, message.clone()),
+    };
+    let current = binding
+        .status
+        .iter()
+        .flat_map(|status| &status.conditions)
+        .find(|condition| condition.type_ == CONDITION_READY);
+    let last_transition_time = match current {
+        Some(current) if current.status == status => current.last_transition_time.clone(),
+        _ => DateTime::from(SystemTime::now())
+            .fmt(Format::DateTime)
+            .unwrap_or_default(),
+    };
+    let condition = BindingCondition {
+        type_: CONDITION_READY.into(),
+        status: status.into(),
+        reason: reason.into(),
+        message,
+        observed_generation: binding.metadata.generation,
+        last_transition_time,
+    };
+    if current == Some(&condition) {
+        return;
+    }
+    let api: Api<AWSRoleBinding> =
+        Api::namespaced(client.clone(), &binding.namespace().unwrap_or_default());
+    let patch = json!({ "status": { "conditions": [condition] } });
+    if let Err(e) = api
+        .patch_status(
+            &binding.name_any(),
+            &PatchParams::default(),
+            &Patch::Merge(&patch),
+        )
+        .await
+    {
+        warn!(
+            "failed to update status of role binding {}/{}: {}",
+            binding.namespace().unwrap_or_default(),
+            binding.name_any(),
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crd::AWSRoleBindingSpec;
+
+    fn binding(name: &str, service_account: &str, aws_role: &str) -> Arc<AWSRoleBinding> {
+        let mut binding = AWSRoleBinding::new(
+            name,
+            AWSRoleBindingSpec {
+                service_account: service_account.into(),
+                aws_role: aws_role.into(),
+                session_name: None,
+                duration_seconds: None,
+            },
+        );
+        binding.metadata.namespace = Some("team".into());
+        Arc::new(binding)
+    }
+
+    #[test]
+    fn resolves_conflicts() {
+        let mut file: Mappings = serde_yaml_ng::from_str(
+            r#"
+annotationPolicies:
+- namespace: team
+  allowedRoles:
+  - arn:aws:iam::123456789012:role/*
+mappings:
+- namespace: team
+  serviceAccount: central
+  awsRole: arn:aws:iam::123456789012:role/central
+- namespace: te*
+  serviceAccount: worker-*
+  awsRole: arn:aws:iam::123456789012:role/workers
+"#,
+        )
+        .unwrap();
+        file.compile().unwrap();
+        let bindings = vec![
+            binding("a", "app", "arn:aws:iam::123456789012:role/a"),
+            binding("b", "app", "arn:aws:iam::123456789012:role/b"),
+            binding("c", "central", "arn:aws:iam::123456789012:role/c"),
+            binding("d", "other", "not-an-arn"),
+            binding("e", "worker-1", "arn:aws:iam::123456789012:role/e"),
+            binding("f", "other", "arn:aws:iam::210987654321:role/f"),
+        ];
+        let resolved = resolve_bindings(&bindings, &file, |_| None);
+        let results: Vec<_> = resolved
+            .iter()
+            .map(|(binding, result)| {
+                (
+                    binding.name_any(),
+                    result
+                        .as_ref()
+                        .map(|m| m.aws_role.as_deref().unwrap_or_default())
+                        .map_err(|e| e.0),
+                )
+            })
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                ("a".to_string(), Ok("arn:aws:iam::123456789012:role/a")),
+                ("b".to_string(), Err("Conflict")),
+                ("c".to_string(), Err("Conflict")),
+                ("d".to_string(), Err("Invalid")),
+                ("e".to_string(), Err("Conflict")),
+                ("f".to_string(), Err("NotAllowed")),
+            ]
+        );
+    }
+}
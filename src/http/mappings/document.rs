@@ -0,0 +1,204 @@
+use std::path::Path;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::validate::{Diagnostic, Severity};
+use super::Mappings;
+
+/// Extensions of mapping files, which select the format they are parsed in
+pub(crate) const EXTENSIONS: &[&str] = &["yaml", "yml", "json", "toml"];
+/// Name without extension of the only file in a mapping directory that may contain
+/// annotation policies, the chart's own mapping file
+pub(crate) const POLICY_FILE: &str = "mappings";
+
+/// Version of the mapping file schema
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub(crate) enum ApiVersion {
+    #[serde(rename = "homelab-aws-creds.io/v1")]
+    V1,
+}
+
+impl ApiVersion {
+    /// Version mapping files are upgraded to when they are loaded
+    pub(crate) const CURRENT: Self = Self::V1;
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::V1 => "homelab-aws-creds.io/v1",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub(crate) enum Kind {
+    RoleMappings,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Format {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl Format {
+    /// Format of a file by its extension, files that are not JSON or TOML are YAML
+    pub(crate) fn of(file: &Path) -> Self {
+        match file.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::Json,
+            Some("toml") => Self::Toml,
+            _ => Self::Yaml,
+        }
+    }
+
+    /// Deserializes the mappings, failing with the line of the problem when it is known
+    pub(crate) fn deserialize(self, contents: &str) -> Result<Mappings, (Option<usize>, String)> {
+        match self {
+            Self::Yaml => serde_yaml_ng::from_str(contents)
+                .map_err(|e| (e.location().map(|location| location.line()), e.to_string())),
+            Self::Json => serde_json::from_str(contents)
+                .map_err(|e| (Some(e.line()).filter(|line| *line > 0), e.to_string())),
+            Self::Toml => toml::from_str(contents).map_err(|e| {
+                let line = e
+                    .span()
+                    .map(|span| contents[..span.start].matches('\n').count() + 1);
+                (line, e.message().to_string())
+            }),
+        }
+    }
+
+    /// Lines of the entries of a top level list. Only YAML block sequences and TOML
+    /// arrays of tables have lines, entries of flow sequences and JSON files have none.
+    pub(crate) fn entry_lines(self, contents: &str, key: &str) -> Vec<usize> {
+        match self {
+            Self::Yaml => yaml_entry_lines(contents, key),
+            Self::Toml => {
+                let header = format!("[[{key}]]");
+                contents
+                    .lines()
+                    .enumerate()
+                    .filter(|(_, line)| line.trim() == header)
+                    .map(|(n, _)| n + 1)
+                    .collect()
+            }
+            Self::Json => vec![],
+        }
+    }
+}
+
+/// Upgrades mappings read in an older version of the schema to the current one. Files
+/// without an apiVersion are in the unversioned format, which only lacks apiVersion
+/// and kind, and are upgraded with a warning so they keep working.
+pub(crate) fn upgrade(mappings: &mut Mappings, file: &Path) -> Vec<Diagnostic> {
+    let current = ApiVersion::CURRENT.as_str();
+    match (mappings.api_version, mappings.kind) {
+        (None, _) => {
+            mappings.api_version = Some(ApiVersion::CURRENT);
+            mappings.kind = Some(Kind::RoleMappings);
+            vec![Diagnostic::document(
+                Severity::Warning,
+                file,
+                "apiVersion",
+                format!(
+                    "unversioned mappings are deprecated, set apiVersion: {current} and kind: RoleMappings"
+                ),
+            )]
+        }
+        (Some(ApiVersion::V1), None) => vec![Diagnostic::document(
+            Severity::Error,
+            file,
+            "kind",
+            "kind must be RoleMappings".into(),
+        )],
+        (Some(ApiVersion::V1), Some(Kind::RoleMappings)) => vec![],
+    }
+}
+
+/// JSON Schema of the current version of mapping files, for validating them in editors
+pub(crate) fn schema() -> Result<String, serde_json::Error> {
+    serde_json::to_string_pretty(&schemars::schema_for!(Mappings))
+}
+
+// Lines of the entries of a top level block sequence, which is how mapping files are
+// written in YAML
+fn yaml_entry_lines(contents: &str, key: &str) -> Vec<usize> {
+    let mut lines = vec![];
+    let mut in_key = false;
+    let mut indent = None;
+    for (n, line) in contents.lines().enumerate() {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let depth = line.len() - trimmed.len();
+        if depth == 0 && !trimmed.starts_with('-') {
+            in_key = trimmed
+                .strip_prefix(key)
+                .is_some_and(|rest| rest.trim_start().starts_with(':'));
+            indent = None;
+        } else if in_key && trimmed.starts_with('-') && *indent.get_or_insert(depth) == depth {
+            lines.push(n + 1);
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_formats() {
+        let toml = r#"
+apiVersion = "homelab-aws-creds.io/v1"
+kind = "RoleMappings"
+
+[[mappings]]
+namespace = "default"
+serviceAccount = "app"
+awsRole = "arn:aws:iam::123456789012:role/app"
+
+[[mappings]]
+namespace = "default"
+serviceAccount = "worker"
+durationSeconds = "900"
+"#;
+        let (line, message) = Format::Toml.deserialize(toml).unwrap_err();
+        assert_eq!(line, Some(13), "{message}");
+        assert_eq!(Format::Toml.entry_lines(toml, "mappings"), [5, 10]);
+
+        let json =
+            r#"{"apiVersion": "homelab-aws-creds.io/v1", "kind": "RoleMappings", "mappings": []}"#;
+        let mappings = Format::Json.deserialize(json).unwrap();
+        assert_eq!(mappings.api_version, Some(ApiVersion::V1));
+        let (line, _) = Format::Json
+            .deserialize("{\n\"apiVersion\": \"v2\"}")
+            .unwrap_err();
+        assert_eq!(line, Some(2));
+    }
+
+    #[test]
+    fn upgrades_unversioned() {
+        let file = Path::new("mappings.yaml");
+        let mut mappings = Format::Yaml.deserialize("mappings: []\n").unwrap();
+        let diagnostics = upgrade(&mut mappings, file);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(mappings.api_version, Some(ApiVersion::CURRENT));
+        assert_eq!(mappings.kind, Some(Kind::RoleMappings));
+
+        let mut mappings = Format::Yaml
+            .deserialize("apiVersion: homelab-aws-creds.io/v1\nmappings: []\n")
+            .unwrap();
+        assert!(upgrade(&mut mappings, file)[0].is_error());
+    }
+
+    #[test]
+    fn published_schema_is_current() {
+        // regenerate with `just mappings-schema`
+        assert_eq!(
+            include_str!("../../../deploy/mappings.schema.json").trim_end(),
+            schema().unwrap()
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use super::matcher::{Matcher, Specificity};
+use super::ServiceRoleMapping;
+use crate::error::Error;
+
+/// Mappings compiled for lookups. Mappings naming both the namespace and service account
+/// are found by hashing, the others are tried from most to least specific.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Index {
+    /// By namespace and then service account
+    exact: HashMap<String, HashMap<String, Arc<ServiceRoleMapping>>>,
+    /// Ordered by specificity and then the order they are listed in
+    patterns: Vec<(Matcher, Arc<ServiceRoleMapping>)>,
+}
+
+impl Index {
+    pub(crate) fn try_new(mappings: &[ServiceRoleMapping]) -> Result<Self, Error> {
+        let mut index = Self::default();
+        for mapping in mappings {
+            let matcher = Matcher::try_new(mapping)?;
+            let mapping = Arc::new(mapping.clone());
+            match matcher.exact() {
+                // the first of duplicate mappings wins
+                Some((namespace, service_account)) => {
+                    index
+                        .exact
+                        .entry(namespace.into())
+                        .or_default()
+                        .entry(service_account.into())
+                        .or_insert(mapping);
+                }
+                None => index.patterns.push((matcher, mapping)),
+            }
+        }
+        // stable so equally specific patterns keep their order
+        index
+            .patterns
+            .sort_by_key(|(matcher, _)| matcher.specificity());
+        Ok(index)
+    }
+
+    /// Mapping naming the namespace and service account, which is the most specific
+    /// kind of mapping
+    pub(crate) fn exact(
+        &self,
+        namespace: &str,
+        service_account: &str,
+    ) -> Option<&Arc<ServiceRoleMapping>> {
+        self.exact.get(namespace)?.get(service_account)
+    }
+
+    /// Most specific pattern matching the service account, with its specificity
+    pub(crate) fn pattern<'a>(
+        &self,
+        namespace: &str,
+        service_account: &str,
+        labels: impl Fn() -> Option<&'a BTreeMap<String, String>>,
+    ) -> Option<(&Arc<ServiceRoleMapping>, Specificity)> {
+        self.patterns
+            .iter()
+            .find(|(matcher, _)| matcher.matches(namespace, service_account, &labels))
+            .map(|(matcher, mapping)| (mapping, matcher.specificity()))
+    }
+
+    /// Most specific mapping of the service account
+    pub(crate) fn lookup<'a>(
+        &self,
+        namespace: &str,
+        service_account: &str,
+        labels: impl Fn() -> Option<&'a BTreeMap<String, String>>,
+    ) -> Option<&Arc<ServiceRoleMapping>> {
+        self.exact(namespace, service_account).or_else(|| {
+            self.pattern(namespace, service_account, labels)
+                .map(|(mapping, _)| mapping)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_patterns_by_specificity() {
+        let mappings: Vec<ServiceRoleMapping> = serde_yaml_ng::from_str(
+            r#"
+- namespaceRegex: team-.*
+  serviceAccount: app
+  awsRole: regex
+- namespace: team-*
+  serviceAccount: "*"
+  awsRole: glob
+- namespace: team-a
+  serviceAccount: app
+  awsRole: exact
+"#,
+        )
+        .unwrap();
+        let index = Index::try_new(&mappings).unwrap();
+        let role = |namespace, service_account| {
+            index
+                .lookup(namespace, service_account, || None)
+                .and_then(|mapping| mapping.aws_role.as_deref())
+        };
+        assert_eq!(role("team-a", "app"), Some("exact"));
+        assert_eq!(role("team-b", "app"), Some("glob"));
+        assert_eq!(role("team-b", "worker"), Some("glob"));
+        assert_eq!(role("default", "app"), None);
+    }
+
+    #[test]
+    fn most_specific_glob_wins() {
+        let mappings: Vec<ServiceRoleMapping> = serde_yaml_ng::from_str(
+            r#"
+- namespace: "*"
+  serviceAccount: app
+  awsRole: any
+- namespace: preview-*
+  serviceAccount: app
+  awsRole: preview
+- namespace: preview-*-*
+  serviceAccount: app
+  awsRole: nested
+"#,
+        )
+        .unwrap();
+        let index = Index::try_new(&mappings).unwrap();
+        let role = |namespace| {
+            index
+                .lookup(namespace, "app", || None)
+                .and_then(|mapping| mapping.aws_role.as_deref())
+        };
+        assert_eq!(role("preview-1"), Some("preview"));
+        assert_eq!(role("preview-a-1"), Some("nested"));
+        assert_eq!(role("default"), Some("any"));
+    }
+}
@@ -0,0 +1,309 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use kube::core::{Selector, SelectorExt};
+use regex::Regex;
+
+use super::{AnnotationPolicy, ServiceRoleMapping};
+use crate::error::Error;
+
+/// Compiled form of the namespace and service account a mapping applies to
+#[derive(Clone, Debug)]
+pub(crate) struct Matcher {
+    namespace: NamespaceMatcher,
+    service_account: NameMatcher,
+}
+
+/// Sort key of a matcher, lower is more specific
+pub(crate) type Specificity = (u8, GlobSpecificity, u8, GlobSpecificity);
+
+/// Globs with more literal characters and then fewer wildcards are more specific
+type GlobSpecificity = (Reverse<usize>, usize);
+
+// Variants are ordered from most to least specific
+#[derive(Clone, Debug)]
+enum NamespaceMatcher {
+    Exact(String),
+    Glob(Glob),
+    Regex(Regex),
+    Selector(Selector),
+}
+
+#[derive(Clone, Debug)]
+enum NameMatcher {
+    Exact(String),
+    Glob(Glob),
+}
+
+#[derive(Clone, Debug)]
+struct Glob {
+    regex: Regex,
+    literals: usize,
+    wildcards: usize,
+}
+
+impl Matcher {
+    pub(crate) fn try_new(mapping: &ServiceRoleMapping) -> Result<Self, Error> {
+        let namespace = NamespaceMatcher::try_new(
+            &mapping.namespace,
+            &mapping.namespace_regex,
+            &mapping.namespace_selector,
+        )
+        .map_err(|e| {
+            Error::RoleMappingError(format!(
+                "mapping for service account {}: {e}",
+                mapping.service_account
+            ))
+        })?;
+        let service_account = match glob(&mapping.service_account) {
+            Some(glob) => NameMatcher::Glob(glob),
+            None => NameMatcher::Exact(mapping.service_account.clone()),
+        };
+        Ok(Self {
+            namespace,
+            service_account,
+        })
+    }
+
+    /// Whether the mapping applies to the service account. The namespace's labels
+    /// are only requested for mappings with a namespace selector.
+    pub(crate) fn matches<'a>(
+        &self,
+        namespace: &str,
+        service_account: &str,
+        labels: impl FnOnce() -> Option<&'a BTreeMap<String, String>>,
+    ) -> bool {
+        let service_account_matches = match self.service_account {
+            NameMatcher::Exact(ref name) => name == service_account,
+            NameMatcher::Glob(ref glob) => glob.regex.is_match(service_account),
+        };
+        service_account_matches && self.namespace.matches(namespace, labels)
+    }
+
+    /// Namespace and service account names of a matcher without patterns
+    pub(crate) fn exact(&self) -> Option<(&str, &str)> {
+        match (&self.namespace, &self.service_account) {
+            (NamespaceMatcher::Exact(namespace), NameMatcher::Exact(service_account)) => {
+                Some((namespace, service_account))
+            }
+            _ => None,
+        }
+    }
+
+    /// Sort key of the matcher, lower is more specific. The namespace is compared
+    /// first: exact, glob, regex and then selector, followed by the service account:
+    /// exact and then glob. Globs of the same kind are ranked by their literal
+    /// characters and then their wildcards.
+    pub(crate) fn specificity(&self) -> Specificity {
+        let (namespace, namespace_glob) = match self.namespace {
+            NamespaceMatcher::Exact(_) => (0, Glob::EXACT),
+            NamespaceMatcher::Glob(ref glob) => (1, glob.specificity()),
+            NamespaceMatcher::Regex(_) => (2, Glob::EXACT),
+            NamespaceMatcher::Selector(_) => (3, Glob::EXACT),
+        };
+        let (service_account, service_account_glob) = match self.service_account {
+            NameMatcher::Exact(_) => (0, Glob::EXACT),
+            NameMatcher::Glob(ref glob) => (1, glob.specificity()),
+        };
+        (
+            namespace,
+            namespace_glob,
+            service_account,
+            service_account_glob,
+        )
+    }
+}
+
+/// Compiled form of an annotation policy
+#[derive(Clone, Debug)]
+pub(crate) struct PolicyMatcher {
+    namespace: NamespaceMatcher,
+    allowed_roles: Vec<NameMatcher>,
+}
+
+impl PolicyMatcher {
+    pub(crate) fn try_new(policy: &AnnotationPolicy) -> Result<Self, Error> {
+        let namespace = NamespaceMatcher::try_new(
+            &policy.namespace,
+            &policy.namespace_regex,
+            &policy.namespace_selector,
+        )
+        .map_err(|e| Error::RoleMappingError(format!("annotation policy: {e}")))?;
+        let allowed_roles = policy
+            .allowed_roles
+            .iter()
+            .map(|role| match glob(role) {
+                Some(glob) => NameMatcher::Glob(glob),
+                None => NameMatcher::Exact(role.clone()),
+            })
+            .collect();
+        Ok(Self {
+            namespace,
+            allowed_roles,
+        })
+    }
+
+    /// Whether service accounts in the namespace may claim the role
+    pub(crate) fn allows<'a>(
+        &self,
+        namespace: &str,
+        role: &str,
+        labels: impl FnOnce() -> Option<&'a BTreeMap<String, String>>,
+    ) -> bool {
+        self.allows_role(role) && self.namespace.matches(namespace, labels)
+    }
+
+    /// Whether service accounts in any of the namespaces may claim the role
+    pub(crate) fn allows_role(&self, role: &str) -> bool {
+        self.allowed_roles.iter().any(|allowed| match allowed {
+            NameMatcher::Exact(name) => name == role,
+            NameMatcher::Glob(glob) => glob.regex.is_match(role),
+        })
+    }
+}
+
+impl NamespaceMatcher {
+    fn try_new(
+        namespace: &Option<String>,
+        namespace_regex: &Option<String>,
+        namespace_selector: &Option<LabelSelector>,
+    ) -> Result<Self, String> {
+        match (namespace, namespace_regex, namespace_selector) {
+            (Some(namespace), None, None) => Ok(match glob(namespace) {
+                Some(glob) => NamespaceMatcher::Glob(glob),
+                None => NamespaceMatcher::Exact(namespace.clone()),
+            }),
+            (None, Some(regex), None) => Regex::new(&format!("^(?:{regex})$"))
+                .map(NamespaceMatcher::Regex)
+                .map_err(|e| format!("invalid namespaceRegex {regex}: {e}")),
+            (None, None, Some(selector)) => Selector::try_from(selector.clone())
+                .map(NamespaceMatcher::Selector)
+                .map_err(|e| format!("invalid namespaceSelector: {e}")),
+            _ => Err(
+                "exactly one of namespace, namespaceRegex or namespaceSelector must be set".into(),
+            ),
+        }
+    }
+
+    fn matches<'a>(
+        &self,
+        namespace: &str,
+        labels: impl FnOnce() -> Option<&'a BTreeMap<String, String>>,
+    ) -> bool {
+        match self {
+            NamespaceMatcher::Exact(name) => name == namespace,
+            NamespaceMatcher::Glob(glob) => glob.regex.is_match(namespace),
+            NamespaceMatcher::Regex(regex) => regex.is_match(namespace),
+            NamespaceMatcher::Selector(selector) => {
+                labels().is_some_and(|labels| selector.matches(labels))
+            }
+        }
+    }
+}
+
+impl Glob {
+    // names are ranked with the kind of matcher alone
+    const EXACT: GlobSpecificity = (Reverse(0), 0);
+
+    fn specificity(&self) -> GlobSpecificity {
+        (Reverse(self.literals), self.wildcards)
+    }
+}
+
+// Names containing `*` or `?` are globs, neither is valid in Kubernetes names
+fn glob(pattern: &str) -> Option<Glob> {
+    if !pattern.contains(['*', '?']) {
+        return None;
+    }
+    let mut regex = String::from("^");
+    let (mut literals, mut wildcards) = (0, 0);
+    for c in pattern.chars() {
+        match c {
+            '*' | '?' => {
+                regex.push_str(if c == '*' { ".*" } else { "." });
+                wildcards += 1;
+            }
+            c => {
+                regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4])));
+                literals += 1;
+            }
+        }
+    }
+    regex.push('$');
+    Some(Glob {
+        regex: Regex::new(&regex).expect("escaped glob is a valid regex"),
+        literals,
+        wildcards,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(yaml: &str) -> Matcher {
+        Matcher::try_new(&serde_yaml_ng::from_str(yaml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn matches_patterns() {
+        let no_labels = || None;
+        let glob = matcher("namespace: preview-*\nserviceAccount: app\nawsRole: role");
+        assert!(glob.matches("preview-123", "app", no_labels));
+        assert!(!glob.matches("preview", "app", no_labels));
+        assert!(!glob.matches("preview-123", "other", no_labels));
+
+        let regex = matcher("namespaceRegex: team-(a|b)\nserviceAccount: ci-?\nawsRole: role");
+        assert!(regex.matches("team-a", "ci-1", no_labels));
+        assert!(!regex.matches("team-ab", "ci-1", no_labels));
+        assert!(!regex.matches("team-a", "ci-12", no_labels));
+
+        let selector = matcher(
+            "namespaceSelector:\n  matchLabels:\n    env: preview\nserviceAccount: '*'\nawsRole: role",
+        );
+        let labels = BTreeMap::from([("env".to_string(), "preview".to_string())]);
+        assert!(selector.matches("anything", "app", || Some(&labels)));
+        assert!(!selector.matches("anything", "app", no_labels));
+    }
+
+    #[test]
+    fn requires_one_namespace_matcher() {
+        for yaml in [
+            "serviceAccount: app\nawsRole: role",
+            "namespace: a\nnamespaceRegex: b\nserviceAccount: app\nawsRole: role",
+        ] {
+            assert!(Matcher::try_new(&serde_yaml_ng::from_str(yaml).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn specificity_order() {
+        let specificity = |yaml: &str| matcher(yaml).specificity();
+        let exact = specificity("namespace: a\nserviceAccount: app\nawsRole: role");
+        let sa_glob = specificity("namespace: a\nserviceAccount: app-*\nawsRole: role");
+        let ns_glob = specificity("namespace: a-*\nserviceAccount: app\nawsRole: role");
+        let regex = specificity("namespaceRegex: a.*\nserviceAccount: app\nawsRole: role");
+        assert!(exact < sa_glob && sa_glob < ns_glob && ns_glob < regex);
+
+        let prefix = specificity("namespace: preview-*\nserviceAccount: app\nawsRole: role");
+        let any = specificity("namespace: '*'\nserviceAccount: app\nawsRole: role");
+        let two = specificity("namespace: preview-*-*\nserviceAccount: app\nawsRole: role");
+        let longer = specificity("namespace: preview-a*\nserviceAccount: app\nawsRole: role");
+        assert!(longer < two && two < prefix && prefix < any);
+    }
+
+    #[test]
+    fn policy_allows_roles() {
+        let policy: AnnotationPolicy = serde_yaml_ng::from_str(
+            "namespace: team-*\nallowedRoles:\n- arn:aws:iam::1:role/team-*\n- arn:aws:iam::1:role/shared",
+        )
+        .unwrap();
+        let policy = PolicyMatcher::try_new(&policy).unwrap();
+        let no_labels = || None;
+        assert!(policy.allows("team-a", "arn:aws:iam::1:role/team-a", no_labels));
+        assert!(policy.allows("team-b", "arn:aws:iam::1:role/shared", no_labels));
+        assert!(!policy.allows("team-a", "arn:aws:iam::1:role/admin", no_labels));
+        assert!(!policy.allows("default", "arn:aws:iam::1:role/shared", no_labels));
+    }
+}
@@ -0,0 +1,838 @@
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Debug;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+use arc_swap::ArcSwapAny;
+use futures_util::StreamExt;
+use k8s_openapi::api::core::v1::Namespace;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use kube::core::PartialObjectMeta;
+use kube::runtime::reflector::{self, ObjectRef, Store};
+use kube::runtime::{metadata_watcher, watcher, WatchStreamExt};
+use kube::{Api, Client as KubeClient, Resource};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use crate::config::CommonConfig;
+use crate::error::Error;
+
+use super::health::{CheckStatus, HealthChecks, StatusCheck};
+use super::util::ReloadableFiles;
+use annotated::AnnotatedRoles;
+pub(crate) use document::schema;
+use document::{ApiVersion, Kind};
+use index::Index;
+use matcher::PolicyMatcher;
+pub(crate) use validate::{Diagnostic, Provenance};
+
+mod annotated;
+mod bindings;
+mod document;
+mod index;
+mod matcher;
+mod validate;
+
+#[derive(Clone)]
+pub(crate) struct Mapping {
+    pub mappings: Arc<ArcSwapAny<Arc<Mappings>>>,
+    /// Active mappings from AWSRoleBinding resources, empty unless they are watched
+    bindings: Arc<ArcSwapAny<Arc<Mappings>>>,
+    /// Namespaces for evaluating namespace selectors, unset unless they are watched
+    namespaces: Arc<OnceLock<Store<PartialObjectMeta<Namespace>>>>,
+    /// Roles annotated on service accounts, unset unless they are watched
+    annotated_roles: Arc<OnceLock<Arc<AnnotatedRoles>>>,
+    /// Hashes of the denied service account annotations that were reported, so they
+    /// are only logged once until the mappings are reloaded
+    denied_annotations: Arc<Mutex<HashSet<u64>>>,
+    path: PathBuf,
+    reloaded: Arc<watch::Sender<()>>,
+    load_status: StatusCheck,
+}
+
+impl Mapping {
+    pub(crate) async fn try_new_from_file(path: PathBuf) -> Result<Self, Error> {
+        let mappings = load_mappings(&path).await?;
+        let mapping = Mapping {
+            mappings: Arc::new(ArcSwapAny::new(Arc::new(mappings))),
+            bindings: Arc::new(ArcSwapAny::new(Arc::new(Mappings::default()))),
+            namespaces: Arc::new(OnceLock::new()),
+            annotated_roles: Arc::new(OnceLock::new()),
+            denied_annotations: Arc::new(Mutex::new(HashSet::new())),
+            path: path.clone(),
+            reloaded: Arc::new(watch::Sender::new(())),
+            load_status: StatusCheck::new("mappings"),
+        };
+        mapping
+            .load_status
+            .set(CheckStatus::ready(format!("loaded from {path:?}")));
+
+        tokio::spawn(ReloadableFiles::new("mappings", vec![path]).watch({
+            let mapping = mapping.clone();
+            // failures are logged and reported by the readiness check
+            move || {
+                let mapping = mapping.clone();
+                async move { mapping.reload().await }
+            }
+        }));
+        Ok(mapping)
+    }
+
+    /// Starts the mapping sources in the cluster that are enabled in the config and
+    /// registers their readiness checks
+    pub(crate) fn watch_cluster(
+        &self,
+        client: KubeClient,
+        cfg: &CommonConfig,
+        health_checks: &HealthChecks,
+    ) {
+        if cfg.namespace_selectors {
+            let (store, ready) = watch_metadata::<Namespace>(client.clone(), "namespaces");
+            let _ = self.namespaces.set(store);
+            health_checks.register(ready);
+        }
+        if cfg.service_account_annotations {
+            let roles = Arc::new(AnnotatedRoles::default());
+            health_checks.register(roles.watch(client.clone(), self.reloaded.clone()));
+            let _ = self.annotated_roles.set(roles);
+        }
+        if cfg.role_bindings {
+            health_checks.register(self.watch_role_bindings(client, cfg.role_binding_status));
+        }
+    }
+
+    /// Reloads the mappings from their file. The current mappings are kept when the
+    /// file cannot be loaded.
+    pub(crate) async fn reload(&self) -> Result<(), Error> {
+        match load_mappings(&self.path).await {
+            Ok(m) => {
+                info!("reloading role mappings");
+                self.store(m);
+                self.load_status
+                    .set(CheckStatus::ready(format!("reloaded from {:?}", self.path)));
+                Ok(())
+            }
+            Err(e) => {
+                error!("failed to reload mappings config: {}", e);
+                self.load_status.set(CheckStatus::not_ready(format!(
+                    "failed to reload mappings: {e}"
+                )));
+                Err(e)
+            }
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns a receiver that is notified every time new mappings are stored or the
+    /// roles annotated on service accounts change
+    pub(crate) fn subscribe(&self) -> watch::Receiver<()> {
+        self.reloaded.subscribe()
+    }
+
+    /// Readiness check reporting whether the last load of the mappings succeeded
+    pub(crate) fn readiness_check(&self) -> StatusCheck {
+        self.load_status.clone()
+    }
+
+    /// Active mappings from AWSRoleBinding resources
+    pub(crate) fn role_bindings(&self) -> Arc<Mappings> {
+        self.bindings.load_full()
+    }
+
+    fn store(&self, mappings: Mappings) {
+        self.mappings.store(Arc::new(mappings));
+        // the policies may allow the annotations now, or deny them again
+        self.denied_annotations
+            .lock()
+            .expect("denied annotations lock poisoned")
+            .clear();
+        self.reloaded.send_replace(());
+    }
+
+    fn store_bindings(&self, bindings: Mappings) {
+        self.bindings.store(Arc::new(bindings));
+        self.reloaded.send_replace(());
+    }
+
+    /// Looks up the mapping for a service account. A role annotated on the service
+    /// account is used when an annotation policy allows it. Otherwise the most specific
+    /// mapping of the file wins, see [`matcher::Matcher::specificity`], and role bindings
+    /// are only used for service accounts the file does not map at all. Equally specific
+    /// mappings are used in the order they are listed. Lookups do not allocate, annotated
+    /// roles are compiled when their service accounts change.
+    #[tracing::instrument(name = "mapping_lookup", skip(self))]
+    pub(crate) fn get_role(
+        &self,
+        namespace: &str,
+        service_account: &str,
+    ) -> Option<Arc<ServiceRoleMapping>> {
+        let namespace_meta = OnceCell::new();
+        let labels = || {
+            namespace_meta
+                .get_or_init(|| self.namespace_meta(namespace))
+                .as_ref()?
+                .metadata
+                .labels
+                .as_ref()
+        };
+        let file = self.mappings.load();
+        if let Some(mapping) = self.annotated_role(&file, namespace, service_account, labels) {
+            return Some(mapping);
+        }
+        if let Some(mapping) = file.index.lookup(namespace, service_account, labels) {
+            return Some(mapping.clone());
+        }
+        self.bindings
+            .load()
+            .index
+            .lookup(namespace, service_account, labels)
+            .cloned()
+    }
+
+    /// Metadata of the namespace, once namespaces are watched
+    fn namespace_meta(&self, namespace: &str) -> Option<Arc<PartialObjectMeta<Namespace>>> {
+        self.namespaces.get()?.get(&ObjectRef::new(namespace))
+    }
+
+    /// Roles granted by the mapping file, role bindings and the service account
+    /// annotations the annotation policies allow
+    pub(crate) fn grants(&self) -> BTreeSet<Grant> {
+        let file = self.mappings.load();
+        let bindings = self.bindings.load();
+        let annotated = self
+            .annotated_roles
+            .get()
+            .map(|roles| roles.mappings())
+            .unwrap_or_default();
+        let allowed = annotated.iter().filter(|mapping| {
+            let namespace = mapping.namespace.as_deref().unwrap_or_default();
+            let meta = self.namespace_meta(namespace);
+            let labels = || meta.as_ref()?.metadata.labels.as_ref();
+            mapping
+                .aws_role
+                .as_deref()
+                .is_some_and(|role| annotation_allowed(&file, namespace, role, labels))
+        });
+        file.mappings
+            .iter()
+            .chain(&bindings.mappings)
+            .chain(allowed.map(AsRef::as_ref))
+            .flat_map(ServiceRoleMapping::grants)
+            .collect()
+    }
+
+    fn annotated_role<'a>(
+        &self,
+        file: &Mappings,
+        namespace: &str,
+        service_account: &str,
+        labels: impl Fn() -> Option<&'a BTreeMap<String, String>>,
+    ) -> Option<Arc<ServiceRoleMapping>> {
+        let mapping = self
+            .annotated_roles
+            .get()?
+            .get(namespace, service_account)?;
+        let role = mapping.aws_role.as_deref()?;
+        if !annotation_allowed(file, namespace, role, labels) {
+            let mut hasher = DefaultHasher::new();
+            (namespace, service_account, role).hash(&mut hasher);
+            let first = self
+                .denied_annotations
+                .lock()
+                .expect("denied annotations lock poisoned")
+                .insert(hasher.finish());
+            if first {
+                warn!(
+                    "role {} annotated on service account {}/{} is not allowed by any annotation policy",
+                    role, namespace, service_account
+                );
+            }
+            return None;
+        }
+        Some(mapping)
+    }
+}
+
+// whether an annotation policy of the file allows service accounts in the namespace to
+// claim the role
+fn annotation_allowed<'a>(
+    file: &Mappings,
+    namespace: &str,
+    role: &str,
+    labels: impl Fn() -> Option<&'a BTreeMap<String, String>>,
+) -> bool {
+    file.policies
+        .iter()
+        .any(|policy| policy.allows(namespace, role, &labels))
+}
+
+// Keeps a cache of the metadata of all objects of a kind, returning it with a readiness
+// check reporting whether they have been listed
+fn watch_metadata<K>(
+    client: KubeClient,
+    name: &'static str,
+) -> (Store<PartialObjectMeta<K>>, StatusCheck)
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+{
+    let ready = StatusCheck::new(name);
+    let (reader, writer) = reflector::store();
+    let mut events = metadata_watcher(Api::<K>::all(client), watcher::Config::default())
+        .default_backoff()
+        .reflect(writer)
+        .touched_objects()
+        .boxed();
+    tokio::spawn({
+        let reader = reader.clone();
+        let ready = ready.clone();
+        async move {
+            if reader.wait_until_ready().await.is_ok() {
+                ready.set(CheckStatus::ready(format!("{name} listed")));
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(event) = events.next().await {
+            if let Err(e) = event {
+                warn!("failed to watch {}: {}", name, e);
+            }
+        }
+    });
+    (reader, ready)
+}
+
+/// Maps service accounts to AWS roles, as written in a mapping file
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Mappings {
+    /// Files without a version are in the unversioned format and upgraded when loaded
+    #[serde(default)]
+    pub api_version: Option<ApiVersion>,
+    #[serde(default)]
+    pub kind: Option<Kind>,
+    pub mappings: Vec<ServiceRoleMapping>,
+    /// Bounds the roles service accounts may claim with an annotation
+    #[serde(default)]
+    pub annotation_policies: Vec<AnnotationPolicy>,
+    /// Where each of the mappings was loaded from, empty for role bindings
+    #[serde(skip)]
+    pub provenance: Vec<Provenance>,
+    #[serde(skip)]
+    index: Index,
+    #[serde(skip)]
+    policies: Vec<PolicyMatcher>,
+    /// sha256 of the file contents the mappings were loaded from
+    #[serde(skip)]
+    pub hash: String,
+    #[serde(skip, default = "SystemTime::now")]
+    pub loaded_at: SystemTime,
+}
+
+impl Default for Mappings {
+    fn default() -> Self {
+        Self {
+            api_version: None,
+            kind: None,
+            mappings: vec![],
+            annotation_policies: vec![],
+            provenance: vec![],
+            index: Index::default(),
+            policies: vec![],
+            hash: String::new(),
+            loaded_at: SystemTime::now(),
+        }
+    }
+}
+
+impl Mappings {
+    pub(crate) fn try_new(mappings: Vec<ServiceRoleMapping>) -> Result<Self, Error> {
+        let mut mappings = Mappings {
+            mappings,
+            ..Default::default()
+        };
+        let errors: Vec<_> = validate::validate(&mappings)
+            .into_iter()
+            .filter(Diagnostic::is_error)
+            .map(|diagnostic| format!("{}: {}", diagnostic.path, diagnostic.message))
+            .collect();
+        if !errors.is_empty() {
+            return Err(Error::RoleMappingError(errors.join(", ")));
+        }
+        mappings.compile()?;
+        Ok(mappings)
+    }
+
+    // appends the mappings and policies of another file
+    fn merge(&mut self, other: Mappings) {
+        self.mappings.extend(other.mappings);
+        self.provenance.extend(other.provenance);
+        self.annotation_policies.extend(other.annotation_policies);
+    }
+
+    fn compile(&mut self) -> Result<(), Error> {
+        self.index = Index::try_new(&self.mappings)?;
+        self.policies = self
+            .annotation_policies
+            .iter()
+            .map(PolicyMatcher::try_new)
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+}
+
+/// Maps service accounts to a role. Namespaces are selected by exactly one of
+/// `namespace`, `namespaceRegex` or `namespaceSelector`. Namespace and service account
+/// names containing `*` or `?` are globs.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ServiceRoleMapping {
+    pub service_account: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// Regex the whole namespace name has to match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace_regex: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace_selector: Option<LabelSelector>,
+    /// Default role, unless one of `roles` is marked as the default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aws_role: Option<String>,
+    /// Additional roles served under their name
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<NamedRole>,
+    /// Session name used when assuming the role, defaults to `<namespace>-<serviceAccount>`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(length(min = 2, max = 64))]
+    pub session_name: Option<String>,
+    /// Lifetime of the issued credentials in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(range(min = 900, max = 43200))]
+    pub duration_seconds: Option<i32>,
+}
+
+impl ServiceRoleMapping {
+    /// Selects the default role, or the named role for an alias. Session settings of
+    /// a named role fall back to the mapping's.
+    pub(crate) fn role(&self, alias: Option<&str>) -> Option<SelectedRole<'_>> {
+        let named = match (alias, &self.aws_role) {
+            (Some(alias), _) => self.roles.iter().find(|role| role.name == alias)?,
+            (None, Some(aws_role)) => {
+                return Some(SelectedRole {
+                    aws_role,
+                    session_name: self.session_name.as_deref(),
+                    duration_seconds: self.duration_seconds,
+                })
+            }
+            (None, None) => self.roles.iter().find(|role| role.default)?,
+        };
+        Some(SelectedRole {
+            aws_role: &named.aws_role,
+            session_name: named
+                .session_name
+                .as_deref()
+                .or(self.session_name.as_deref()),
+            duration_seconds: named.duration_seconds.or(self.duration_seconds),
+        })
+    }
+
+    /// Names of the additional roles
+    pub(crate) fn aliases(&self) -> Vec<&str> {
+        self.roles.iter().map(|role| role.name.as_str()).collect()
+    }
+
+    /// Namespaces and service accounts the mapping selects, e.g. `team-*/app`. Regexes
+    /// are prefixed with `~` and selectors are shown as JSON.
+    pub(crate) fn subject(&self) -> String {
+        let namespace = match (
+            &self.namespace,
+            &self.namespace_regex,
+            &self.namespace_selector,
+        ) {
+            (Some(namespace), _, _) => namespace.clone(),
+            (_, Some(regex), _) => format!("~{regex}"),
+            (_, _, Some(selector)) => serde_json::to_string(selector).unwrap_or_default(),
+            _ => String::new(),
+        };
+        format!("{namespace}/{}", self.service_account)
+    }
+
+    fn grants(&self) -> impl Iterator<Item = Grant> + '_ {
+        let subject = self.subject();
+        self.aws_role
+            .iter()
+            .chain(self.roles.iter().map(|role| &role.aws_role))
+            .map(move |aws_role| Grant {
+                subject: subject.clone(),
+                aws_role: aws_role.clone(),
+            })
+    }
+}
+
+/// Role granted to the service accounts selected by a mapping. Compared across reloads
+/// to find the roles that were unmapped.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct Grant {
+    /// See [`ServiceRoleMapping::subject`]
+    pub subject: String,
+    pub aws_role: String,
+}
+
+/// Role of a service account selected by its name
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NamedRole {
+    pub name: String,
+    pub aws_role: String,
+    /// Serve the role when no name is requested instead of the mapping's awsRole
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub default: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(length(min = 2, max = 64))]
+    pub session_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(range(min = 900, max = 43200))]
+    pub duration_seconds: Option<i32>,
+}
+
+/// Role and session settings to assume for a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SelectedRole<'a> {
+    pub aws_role: &'a str,
+    pub session_name: Option<&'a str>,
+    pub duration_seconds: Option<i32>,
+}
+
+/// Allows service accounts in the selected namespaces to claim roles matching
+/// `allowedRoles` with the `eks.amazonaws.com/role-arn` annotation. Roles are ARNs or
+/// globs.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AnnotationPolicy {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace_regex: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace_selector: Option<LabelSelector>,
+    pub allowed_roles: Vec<String>,
+}
+
+/// Loads the mapping file, or merges every mapping file in a directory. Fails with
+/// the errors found when any file is invalid.
+pub(crate) async fn load_mappings(path: impl AsRef<Path>) -> Result<Mappings, Error> {
+    let path = path.as_ref();
+    let (mappings, diagnostics) = read_mappings(path).await?;
+    let (errors, warnings): (Vec<_>, Vec<_>) =
+        diagnostics.into_iter().partition(Diagnostic::is_error);
+    for warning in warnings {
+        warn!("{}", warning);
+    }
+    match mappings {
+        Some(mappings) if errors.is_empty() => Ok(mappings),
+        _ => {
+            let errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+            Err(Error::RoleMappingError(format!(
+                "invalid mappings in {path:?}: {}",
+                errors.join(", ")
+            )))
+        }
+    }
+}
+
+/// Diagnostics of a mapping file or directory, errors among them cause it to be rejected
+pub(crate) async fn validate_file(path: &Path) -> Result<Vec<Diagnostic>, Error> {
+    Ok(read_mappings(path).await?.1)
+}
+
+// Mappings are only returned when all files are valid
+async fn read_mappings(path: &Path) -> Result<(Option<Mappings>, Vec<Diagnostic>), Error> {
+    let mut hasher = Sha256::new();
+    let mut merged = Some(Mappings::default());
+    let mut diagnostics = vec![];
+    let directory = tokio::fs::metadata(path).await?.is_dir();
+    for file in mapping_files(path).await? {
+        let contents = tokio::fs::read_to_string(&file).await?;
+        hasher.update(file.as_os_str().as_encoded_bytes());
+        hasher.update(contents.as_bytes());
+        match validate::parse(&contents, &file) {
+            Ok((mappings, warnings)) => {
+                diagnostics.extend(warnings);
+                let policies = directory
+                    .then(|| validate::policies_outside_policy_file(&mappings, &file))
+                    .flatten();
+                if let Some(error) = policies {
+                    diagnostics.push(error);
+                    merged = None;
+                } else if let Some(ref mut merged) = merged {
+                    merged.merge(mappings);
+                }
+            }
+            Err(errors) => {
+                diagnostics.extend(errors);
+                merged = None;
+            }
+        }
+    }
+    let Some(mut mappings) = merged else {
+        return Ok((None, diagnostics));
+    };
+    let conflicts = validate::conflicts_between_files(&mappings);
+    let valid = !conflicts.iter().any(Diagnostic::is_error);
+    diagnostics.extend(conflicts);
+    if !valid {
+        return Ok((None, diagnostics));
+    }
+    mappings.compile()?;
+    mappings.hash = format!("{:x}", hasher.finalize());
+    Ok((Some(mappings), diagnostics))
+}
+
+/// The path itself, or the YAML, JSON and TOML files in it when it is a directory,
+/// sorted by name. Hidden files are skipped, which includes the `..data` directories of ConfigMap
+/// and Secret mounts.
+pub(crate) async fn mapping_files(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !tokio::fs::metadata(path).await?.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = vec![];
+    let mut entries = tokio::fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        let mapping_file = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| document::EXTENSIONS.contains(&ext));
+        if !hidden && mapping_file && tokio::fs::metadata(&path).await?.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(file: &str, bindings: &str) -> Mapping {
+        let file: Mappings = serde_yaml_ng::from_str(file).unwrap();
+        let bindings: Mappings = serde_yaml_ng::from_str(bindings).unwrap();
+        Mapping {
+            mappings: Arc::new(ArcSwapAny::new(Arc::new(
+                Mappings::try_new(file.mappings).unwrap(),
+            ))),
+            bindings: Arc::new(ArcSwapAny::new(Arc::new(
+                Mappings::try_new(bindings.mappings).unwrap(),
+            ))),
+            namespaces: Arc::new(OnceLock::new()),
+            annotated_roles: Arc::new(OnceLock::new()),
+            denied_annotations: Arc::new(Mutex::new(HashSet::new())),
+            path: PathBuf::new(),
+            reloaded: Arc::new(watch::Sender::new(())),
+            load_status: StatusCheck::new("mappings"),
+        }
+    }
+
+    #[test]
+    fn most_specific_match_wins() {
+        let mapping = mapping(
+            r#"
+mappings:
+- namespaceRegex: preview-.*
+  serviceAccount: app
+  awsRole: arn:aws:iam::123456789012:role/regex
+- namespace: preview-*
+  serviceAccount: "*"
+  awsRole: arn:aws:iam::123456789012:role/glob
+- namespace: preview-*
+  serviceAccount: app
+  awsRole: arn:aws:iam::123456789012:role/first
+- namespace: preview-?
+  serviceAccount: app
+  awsRole: arn:aws:iam::123456789012:role/second
+"#,
+            r#"
+mappings:
+- namespace: preview-1
+  serviceAccount: app
+  awsRole: arn:aws:iam::123456789012:role/shadowed
+- namespace: default
+  serviceAccount: app
+  awsRole: arn:aws:iam::123456789012:role/binding
+"#,
+        );
+        let role = |namespace, service_account| {
+            mapping
+                .get_role(namespace, service_account)
+                .and_then(|m| m.aws_role.clone())
+                .and_then(|arn| {
+                    arn.strip_prefix("arn:aws:iam::123456789012:role/")
+                        .map(String::from)
+                })
+        };
+        // the file wins over bindings however specific they are
+        assert_eq!(role("preview-1", "app").as_deref(), Some("first"));
+        assert_eq!(role("preview-2", "app").as_deref(), Some("first"));
+        assert_eq!(role("preview-2", "worker").as_deref(), Some("glob"));
+        assert_eq!(role("default", "app").as_deref(), Some("binding"));
+        assert_eq!(role("default", "worker"), None);
+    }
+
+    #[test]
+    fn selects_named_roles() {
+        let mappings: Mappings = serde_yaml_ng::from_str(
+            r#"
+mappings:
+- namespace: default
+  serviceAccount: app
+  sessionName: app
+  roles:
+  - name: bucket
+    awsRole: arn:aws:iam::123456789012:role/bucket
+    default: true
+  - name: kms
+    awsRole: arn:aws:iam::123456789012:role/kms
+    durationSeconds: 900
+"#,
+        )
+        .unwrap();
+        let mapping = &Mappings::try_new(mappings.mappings).unwrap().mappings[0];
+        assert_eq!(
+            mapping.role(None),
+            Some(SelectedRole {
+                aws_role: "arn:aws:iam::123456789012:role/bucket",
+                session_name: Some("app"),
+                duration_seconds: None,
+            })
+        );
+        assert_eq!(
+            mapping.role(Some("kms")).map(|role| role.duration_seconds),
+            Some(Some(900))
+        );
+        assert_eq!(mapping.role(Some("other")), None);
+        assert_eq!(mapping.aliases(), vec!["bucket", "kms"]);
+
+        for invalid in [
+            "mappings:\n- namespace: a\n  serviceAccount: b\n",
+            "mappings:\n- namespace: a\n  serviceAccount: b\n  awsRole: arn:aws:iam::123456789012:role/r\n  roles:\n  - name: x\n    awsRole: arn:aws:iam::123456789012:role/r\n    default: true\n",
+            "mappings:\n- namespace: a\n  serviceAccount: b\n  awsRole: arn:aws:iam::123456789012:role/r\n  roles:\n  - name: X_Y\n    awsRole: arn:aws:iam::123456789012:role/r\n",
+        ] {
+            let mappings: Mappings = serde_yaml_ng::from_str(invalid).unwrap();
+            assert!(Mappings::try_new(mappings.mappings).is_err());
+        }
+    }
+
+    #[test]
+    fn lists_grants() {
+        let mapping = mapping(
+            r#"
+mappings:
+- namespaceRegex: team-.*
+  serviceAccount: app
+  awsRole: arn:aws:iam::123456789012:role/app
+  roles:
+  - name: kms
+    awsRole: arn:aws:iam::123456789012:role/kms
+"#,
+            r#"
+mappings:
+- namespace: team-a
+  serviceAccount: app
+  awsRole: arn:aws:iam::123456789012:role/app
+"#,
+        );
+        let grants: Vec<_> = mapping
+            .grants()
+            .into_iter()
+            .map(|grant| format!("{} {}", grant.subject, grant.aws_role))
+            .collect();
+        assert_eq!(
+            grants,
+            [
+                "team-a/app arn:aws:iam::123456789012:role/app",
+                "~team-.*/app arn:aws:iam::123456789012:role/app",
+                "~team-.*/app arn:aws:iam::123456789012:role/kms",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn merges_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        tokio::fs::create_dir_all(dir.join("..data")).await.unwrap();
+        tokio::fs::write(
+            dir.join("team-a.yaml"),
+            "mappings:\n- namespace: team-a\n  serviceAccount: app\n  awsRole: arn:aws:iam::123456789012:role/a\n",
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(
+            dir.join("team-b.json"),
+            r#"{"mappings": [{"namespace": "team-b", "serviceAccount": "app", "awsRole": "arn:aws:iam::123456789012:role/b"}]}"#,
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(dir.join("README.md"), "not a mapping file")
+            .await
+            .unwrap();
+
+        let mappings = load_mappings(dir).await.unwrap();
+        assert_eq!(mappings.mappings.len(), 2);
+        assert_eq!(mappings.provenance[1].file, dir.join("team-b.json"));
+        assert_eq!(
+            mappings
+                .index
+                .exact("team-b", "app")
+                .and_then(|m| m.aws_role.as_deref()),
+            Some("arn:aws:iam::123456789012:role/b")
+        );
+
+        tokio::fs::write(
+            dir.join("team-c.yaml"),
+            "mappings:\n- namespace: team-a\n  serviceAccount: app\n  awsRole: arn:aws:iam::123456789012:role/c\n",
+        )
+        .await
+        .unwrap();
+        let err = load_mappings(dir).await.unwrap_err().to_string();
+        assert!(
+            err.contains("team-c.yaml:2: error: mappings[0]: conflicts for the same service accounts with mappings[0] in"),
+            "{err}"
+        );
+        tokio::fs::remove_file(dir.join("team-c.yaml"))
+            .await
+            .unwrap();
+
+        let policies =
+            "annotationPolicies:\n- namespace: '*'\n  allowedRoles: ['*']\nmappings: []\n";
+        tokio::fs::write(dir.join("team-c.yaml"), policies)
+            .await
+            .unwrap();
+        let err = load_mappings(dir).await.unwrap_err().to_string();
+        assert!(
+            err.contains(
+                "team-c.yaml: error: annotationPolicies: only allowed in the mappings file"
+            ),
+            "{err}"
+        );
+        tokio::fs::remove_file(dir.join("team-c.yaml"))
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("mappings.yaml"), policies)
+            .await
+            .unwrap();
+        let mappings = load_mappings(dir).await.unwrap();
+        assert_eq!(mappings.annotation_policies.len(), 1);
+    }
+}
@@ -0,0 +1,520 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use super::document::{self, Format};
+use super::matcher::{Matcher, PolicyMatcher};
+use super::{Mappings, ServiceRoleMapping};
+
+/// Partitions a role ARN can be in
+const PARTITIONS: &[&str] = &[
+    "aws",
+    "aws-cn",
+    "aws-us-gov",
+    "aws-iso",
+    "aws-iso-b",
+    "aws-iso-e",
+    "aws-iso-f",
+    "aws-eusc",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Severity {
+    Error,
+    Warning,
+}
+
+/// Where a mapping was loaded from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Provenance {
+    pub file: PathBuf,
+    /// Index of the mapping in the file
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+}
+
+/// Problem found in a mapping config
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Diagnostic {
+    pub severity: Severity,
+    pub file: PathBuf,
+    /// Line of the entry the problem is in, when it is known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    /// Field the problem is in, e.g. `mappings[1].awsRole`
+    pub path: String,
+    pub message: String,
+    // top level list and index of the entry, for looking up its line
+    #[serde(skip)]
+    entry: Option<(&'static str, usize)>,
+}
+
+impl Diagnostic {
+    fn new(severity: Severity, entry: (&'static str, usize), field: &str, message: String) -> Self {
+        Self {
+            severity,
+            file: PathBuf::new(),
+            line: None,
+            path: format!("{}[{}]{field}", entry.0, entry.1),
+            message,
+            entry: Some(entry),
+        }
+    }
+
+    fn file_error(file: &Path, line: Option<usize>, message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            file: file.to_path_buf(),
+            line,
+            path: String::new(),
+            message,
+            entry: None,
+        }
+    }
+
+    pub(super) fn document(severity: Severity, file: &Path, field: &str, message: String) -> Self {
+        Self {
+            severity,
+            file: file.to_path_buf(),
+            line: None,
+            path: field.into(),
+            message,
+            entry: None,
+        }
+    }
+
+    pub(crate) fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}:", self.file.display())?;
+        if let Some(line) = self.line {
+            write!(f, "{line}:")?;
+        }
+        if self.path.is_empty() {
+            write!(f, " {severity}: {}", self.message)
+        } else {
+            write!(f, " {severity}: {}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// Parses and validates the contents of a mapping file in the format of its extension,
+/// upgrading it to the current version. Fails with all diagnostics when there are
+/// errors, otherwise returns the mappings with any warnings. The mappings are not
+/// compiled so files can be merged first.
+pub(crate) fn parse(
+    contents: &str,
+    file: &Path,
+) -> Result<(Mappings, Vec<Diagnostic>), Vec<Diagnostic>> {
+    let format = Format::of(file);
+    let mut mappings = format
+        .deserialize(contents)
+        .map_err(|(line, message)| vec![Diagnostic::file_error(file, line, message)])?;
+    let upgraded = document::upgrade(&mut mappings, file);
+    let lines = [
+        ("mappings", format.entry_lines(contents, "mappings")),
+        (
+            "annotationPolicies",
+            format.entry_lines(contents, "annotationPolicies"),
+        ),
+    ];
+    let line = |(list, index): (&str, usize)| {
+        lines
+            .iter()
+            .find(|(name, _)| *name == list)
+            .and_then(|(_, lines)| lines.get(index).copied())
+    };
+    mappings.provenance = (0..mappings.mappings.len())
+        .map(|index| Provenance {
+            file: file.to_path_buf(),
+            index,
+            line: line(("mappings", index)),
+        })
+        .collect();
+    let mut diagnostics = validate(&mappings);
+    for diagnostic in &mut diagnostics {
+        diagnostic.file = file.to_path_buf();
+        diagnostic.line = diagnostic.entry.and_then(line);
+    }
+    diagnostics.splice(0..0, upgraded);
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(diagnostics);
+    }
+    Ok((mappings, diagnostics))
+}
+
+/// Rejects annotation policies of a file in a mapping directory other than the policy
+/// file, as they would widen the roles service accounts of every namespace may claim
+pub(crate) fn policies_outside_policy_file(mappings: &Mappings, file: &Path) -> Option<Diagnostic> {
+    let policy_file = file
+        .file_stem()
+        .is_some_and(|stem| stem == document::POLICY_FILE);
+    (!policy_file && !mappings.annotation_policies.is_empty()).then(|| {
+        Diagnostic::document(
+            Severity::Error,
+            file,
+            "annotationPolicies",
+            format!(
+                "only allowed in the {} file of a mapping directory",
+                document::POLICY_FILE
+            ),
+        )
+    })
+}
+
+/// Finds mappings in different files for the same service accounts, the problems
+/// within a file are found when parsing it
+pub(crate) fn conflicts_between_files(mappings: &Mappings) -> Vec<Diagnostic> {
+    let provenance = &mappings.provenance;
+    duplicates(&mappings.mappings, |i, j| {
+        provenance[i].file != provenance[j].file
+    })
+    .map(|(i, j, severity)| {
+        let (source, other) = (&provenance[i], &provenance[j]);
+        let message = match severity {
+            Severity::Warning => "duplicate of",
+            Severity::Error => "conflicts for the same service accounts with",
+        };
+        Diagnostic {
+            severity,
+            file: source.file.clone(),
+            line: source.line,
+            path: format!("mappings[{}]", source.index),
+            message: format!(
+                "{message} mappings[{}] in {}",
+                other.index,
+                other.file.display()
+            ),
+            entry: None,
+        }
+    })
+    .collect()
+}
+
+// Mappings for the same service accounts as an earlier one, which wins as it is just as
+// specific. Identical mappings are only a warning. Yields the index of the mapping and
+// of the earlier one.
+fn duplicates<'a>(
+    mappings: &'a [ServiceRoleMapping],
+    compare: impl Fn(usize, usize) -> bool + 'a,
+) -> impl Iterator<Item = (usize, usize, Severity)> + 'a {
+    (0..mappings.len()).filter_map(move |i| {
+        let mapping = &mappings[i];
+        let j = (0..i).find(|&j| compare(i, j) && same_subject(mapping, &mappings[j]))?;
+        let other = &mappings[j];
+        let severity = if other.aws_role == mapping.aws_role && other.roles == mapping.roles {
+            Severity::Warning
+        } else {
+            Severity::Error
+        };
+        Some((i, j, severity))
+    })
+}
+
+/// Checks the mappings and annotation policies beyond what deserializing them does
+pub(crate) fn validate(mappings: &Mappings) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    for (i, mapping) in mappings.mappings.iter().enumerate() {
+        validate_mapping(mapping, ("mappings", i), &mut diagnostics);
+    }
+    for (i, j, severity) in duplicates(&mappings.mappings, |_, _| true) {
+        let message = match severity {
+            Severity::Warning => format!("duplicate of mappings[{j}]"),
+            Severity::Error => {
+                format!("conflicts with mappings[{j}] for the same service accounts")
+            }
+        };
+        diagnostics.push(Diagnostic::new(severity, ("mappings", i), "", message));
+    }
+    for (i, policy) in mappings.annotation_policies.iter().enumerate() {
+        let entry = ("annotationPolicies", i);
+        if let Err(e) = PolicyMatcher::try_new(policy) {
+            diagnostics.push(Diagnostic::new(Severity::Error, entry, "", e.to_string()));
+        }
+        if let Some(Err(e)) = policy.namespace.as_deref().map(namespace_name) {
+            diagnostics.push(Diagnostic::new(Severity::Error, entry, ".namespace", e));
+        }
+        if policy.allowed_roles.is_empty() {
+            diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                entry,
+                ".allowedRoles",
+                "allows no roles".into(),
+            ));
+        }
+        for (j, role) in policy.allowed_roles.iter().enumerate() {
+            if role.contains(['*', '?']) {
+                continue;
+            }
+            if let Err(e) = role_arn(role) {
+                diagnostics.push(Diagnostic::new(
+                    Severity::Error,
+                    entry,
+                    &format!(".allowedRoles[{j}]"),
+                    e,
+                ));
+            }
+        }
+    }
+    diagnostics
+}
+
+fn validate_mapping(
+    mapping: &ServiceRoleMapping,
+    entry: (&'static str, usize),
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let mut error = |field: &str, message: String| {
+        diagnostics.push(Diagnostic::new(Severity::Error, entry, field, message));
+    };
+    if let Err(e) = Matcher::try_new(mapping) {
+        error("", e.to_string());
+    }
+    if let Some(Err(e)) = mapping.namespace.as_deref().map(namespace_name) {
+        error(".namespace", e);
+    }
+    if let Err(e) = service_account_name(&mapping.service_account) {
+        error(".serviceAccount", e);
+    }
+    if let Some(Err(e)) = mapping.aws_role.as_deref().map(role_arn) {
+        error(".awsRole", e);
+    }
+    if let Some(Err(e)) = mapping.session_name.as_deref().map(session_name) {
+        error(".sessionName", e);
+    }
+    if let Some(Err(e)) = mapping.duration_seconds.map(duration_seconds) {
+        error(".durationSeconds", e);
+    }
+    let defaults = usize::from(mapping.aws_role.is_some())
+        + mapping.roles.iter().filter(|role| role.default).count();
+    if defaults != 1 {
+        error(
+            "",
+            "exactly one of awsRole or a role marked as default must be set".into(),
+        );
+    }
+    // role names are used in URL paths and environment variable names
+    for (j, role) in mapping.roles.iter().enumerate() {
+        let field = |name: &str| format!(".roles[{j}]{name}");
+        if let Err(e) = dns_label(&role.name) {
+            error(&field(".name"), format!("role name {e}"));
+        }
+        if mapping.roles[..j]
+            .iter()
+            .any(|other| other.name == role.name)
+        {
+            error(
+                &field(".name"),
+                format!("duplicate role name {}", role.name),
+            );
+        }
+        if let Err(e) = role_arn(&role.aws_role) {
+            error(&field(".awsRole"), e);
+        }
+        if let Some(Err(e)) = role.session_name.as_deref().map(session_name) {
+            error(&field(".sessionName"), e);
+        }
+        if let Some(Err(e)) = role.duration_seconds.map(duration_seconds) {
+            error(&field(".durationSeconds"), e);
+        }
+    }
+}
+
+// Mappings that select the same namespaces and service accounts in the same way
+fn same_subject(a: &ServiceRoleMapping, b: &ServiceRoleMapping) -> bool {
+    a.service_account == b.service_account
+        && a.namespace == b.namespace
+        && a.namespace_regex == b.namespace_regex
+        && a.namespace_selector == b.namespace_selector
+}
+
+/// Checks that an ARN names an IAM role, e.g. `arn:aws:iam::123456789012:role/name`
+pub(crate) fn role_arn(arn: &str) -> Result<(), String> {
+    let invalid = |reason: &str| Err(format!("{arn} is not a valid role ARN: {reason}"));
+    let parts: Vec<&str> = arn.splitn(6, ':').collect();
+    let [prefix, partition, service, region, account, resource] = parts[..] else {
+        return invalid("expected arn:<partition>:iam::<account>:role/<name>");
+    };
+    if prefix != "arn" || service != "iam" || !region.is_empty() {
+        return invalid("expected arn:<partition>:iam::<account>:role/<name>");
+    }
+    if !PARTITIONS.contains(&partition) {
+        return invalid(&format!("unknown partition {partition}"));
+    }
+    if account.len() != 12 || !account.chars().all(|c| c.is_ascii_digit()) {
+        return invalid("account must be 12 digits");
+    }
+    let Some(path) = resource.strip_prefix("role/") else {
+        return invalid("resource must start with role/");
+    };
+    // https://docs.aws.amazon.com/IAM/latest/APIReference/API_Role.html
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let valid_chars = path
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_+=,.@-/".contains(c));
+    if name.is_empty() || name.len() > 64 || path.len() > 512 || !valid_chars {
+        return invalid("invalid role name");
+    }
+    Ok(())
+}
+
+/// Checks a session name against the rules of AssumeRole
+pub(crate) fn session_name(name: &str) -> Result<(), String> {
+    // https://docs.aws.amazon.com/STS/latest/APIReference/API_AssumeRole.html
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_+=,.@-".contains(c));
+    if !(2..=64).contains(&name.len()) || !valid_chars {
+        return Err(format!("sessionName {name} is not a valid session name"));
+    }
+    Ok(())
+}
+
+pub(crate) fn duration_seconds(duration: i32) -> Result<(), String> {
+    if !(900..=43200).contains(&duration) {
+        return Err(format!(
+            "durationSeconds {duration} must be between 900 and 43200"
+        ));
+    }
+    Ok(())
+}
+
+/// Checks a namespace name, or glob of one, against the DNS label rules namespace names
+/// follow
+pub(crate) fn namespace_name(name: &str) -> Result<(), String> {
+    if name.contains(['*', '?']) {
+        let valid_chars = name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-*?".contains(c));
+        if !valid_chars || name.len() > 63 {
+            return Err(format!("namespace glob {name} can never match a namespace"));
+        }
+        return Ok(());
+    }
+    dns_label(name).map_err(|e| format!("namespace {e}"))
+}
+
+/// Checks a service account name, or glob of one, against the DNS subdomain rules
+/// service account names follow
+pub(crate) fn service_account_name(name: &str) -> Result<(), String> {
+    let glob = name.contains(['*', '?']);
+    let valid_chars = name.chars().all(|c| {
+        c.is_ascii_lowercase() || c.is_ascii_digit() || "-.".contains(c) || glob && "*?".contains(c)
+    });
+    let valid_ends = glob
+        || name.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && name.ends_with(|c: char| c.is_ascii_alphanumeric());
+    if name.is_empty() || name.len() > 253 || !valid_chars || !valid_ends {
+        return Err(format!(
+            "service account {name} must be lowercase alphanumeric characters, '-' or '.'"
+        ));
+    }
+    Ok(())
+}
+
+fn dns_label(name: &str) -> Result<(), String> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !(1..=63).contains(&name.len())
+        || !valid_chars
+        || name.starts_with('-')
+        || name.ends_with('-')
+    {
+        return Err(format!(
+            "{name} must be at most 63 lowercase alphanumeric characters or '-'"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_lines() {
+        let contents = r#"
+apiVersion: homelab-aws-creds.io/v1
+kind: RoleMappings
+annotationPolicies:
+  - namespace: team-*
+    allowedRoles: [arn:aws:iam::123:role/short-account]
+mappings:
+  - namespace: default
+    serviceAccount: app
+    awsRole: arn:aws:iam::123456789012:role/app
+    # roles of the worker
+  - namespace: Default
+    serviceAccount: worker
+    awsRole: arn:aws:s3:::bucket
+    roles:
+      - name: kms
+        awsRole: arn:aws-mars:iam::123456789012:role/kms
+  - namespace: default
+    serviceAccount: app
+    awsRole: arn:aws:iam::123456789012:role/other
+"#;
+        let diagnostics = parse(contents, Path::new("mappings.yaml")).unwrap_err();
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.line, d.path.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (Some(12), "mappings[1].namespace"),
+                (Some(12), "mappings[1].awsRole"),
+                (Some(12), "mappings[1].roles[0].awsRole"),
+                (Some(18), "mappings[2]"),
+                (Some(5), "annotationPolicies[0].allowedRoles[0]"),
+            ]
+        );
+        assert!(diagnostics.iter().all(Diagnostic::is_error));
+
+        let err = parse("mappings:\n  - namespace: [\n", Path::new("mappings.yaml")).unwrap_err();
+        assert_eq!(err[0].line, Some(2));
+    }
+
+    #[test]
+    fn warns_about_duplicates() {
+        let entry = "  - namespace: default\n    serviceAccount: app\n    awsRole: arn:aws:iam::123456789012:role/app\n";
+        let (mappings, warnings) = parse(
+            &format!("apiVersion: homelab-aws-creds.io/v1\nkind: RoleMappings\nmappings:\n{entry}{entry}"),
+            Path::new("mappings.yaml"),
+        )
+        .unwrap();
+        assert_eq!(mappings.mappings.len(), 2);
+        assert_eq!(
+            warnings[0].to_string(),
+            "mappings.yaml:7: warning: mappings[1]: duplicate of mappings[0]"
+        );
+    }
+
+    #[test]
+    fn role_arns() {
+        assert!(role_arn("arn:aws:iam::123456789012:role/app").is_ok());
+        assert!(role_arn("arn:aws-cn:iam::123456789012:role/path/to/app").is_ok());
+        for arn in [
+            "app",
+            "arn:aws:iam::123456789012:user/app",
+            "arn:aws:iam:us-east-1:123456789012:role/app",
+            "arn:aws:sts::123456789012:role/app",
+            "arn:aws:iam::123456789012:role/",
+            "arn:aws:iam::123456789012:role/app name",
+        ] {
+            assert!(role_arn(arn).is_err(), "{arn}");
+        }
+    }
+}
@@ -4,51 +4,98 @@ use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
     routing::get,
-    Router,
+    Json, Router,
 };
-use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use tokio_util::sync::CancellationToken;
 
-#[derive(Clone, Debug)]
+use super::health::{CheckStatus, HealthChecks, Heartbeat};
+
+#[derive(Clone)]
 pub(crate) struct StatusHandler {
     ready_token: CancellationToken,
     prom_handler: PrometheusHandle,
+    health_checks: HealthChecks,
+    heartbeat: Heartbeat,
 }
 
-pub(crate) enum Readiness {
-    Ready,
-    NotReady,
+#[derive(Serialize)]
+pub(crate) struct Readiness {
+    ready: bool,
+    checks: BTreeMap<&'static str, CheckStatus>,
 }
 
 impl IntoResponse for Readiness {
+    fn into_response(self) -> axum::response::Response {
+        let status = if self.ready {
+            StatusCode::OK
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+pub(crate) enum Liveness {
+    Alive,
+    Wedged,
+}
+
+impl IntoResponse for Liveness {
     fn into_response(self) -> axum::response::Response {
         match self {
-            Readiness::Ready => Response::builder()
+            Liveness::Alive => Response::builder()
                 .status(StatusCode::OK)
                 .header("Content-Type", "text/plain")
                 .body(axum::body::Body::from("Ok"))
                 .unwrap(),
-            Readiness::NotReady => Response::builder()
+            Liveness::Wedged => Response::builder()
                 .status(StatusCode::INTERNAL_SERVER_ERROR)
                 .header("Content-Type", "text/plain")
-                .body(axum::body::Body::from("NotReady"))
+                .body(axum::body::Body::from("Wedged"))
                 .unwrap(),
         }
     }
 }
+
 impl StatusHandler {
-    pub fn try_new(ready_token: CancellationToken) -> Result<StatusHandler, Error> {
+    pub fn try_new(
+        ready_token: CancellationToken,
+        health_checks: HealthChecks,
+        heartbeat: Heartbeat,
+    ) -> Result<StatusHandler, Error> {
         Ok(Self {
             ready_token,
             prom_handler: setup_metrics()?,
+            health_checks,
+            heartbeat,
         })
     }
+
     pub fn ready(&self) -> Readiness {
-        if self.ready_token.is_cancelled() {
-            Readiness::NotReady
+        let mut checks = self.health_checks.report();
+        checks.insert(
+            "shutdown",
+            if self.ready_token.is_cancelled() {
+                CheckStatus::not_ready("shutting down")
+            } else {
+                CheckStatus::ready("running")
+            },
+        );
+        Readiness {
+            ready: checks.values().all(|c| c.ready),
+            checks,
+        }
+    }
+
+    pub fn live(&self) -> Liveness {
+        if self.heartbeat.alive() {
+            Liveness::Alive
         } else {
-            Readiness::Ready
+            Liveness::Wedged
         }
     }
 
@@ -57,14 +104,25 @@ impl StatusHandler {
     }
 }
 
+// buckets for request, TokenReview and STS latencies in seconds
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
 fn setup_metrics() -> Result<PrometheusHandle, Error> {
-    Ok(PrometheusBuilder::new().install_recorder()?)
+    Ok(PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Suffix("duration_seconds".to_string()),
+            DURATION_BUCKETS,
+        )?
+        .install_recorder()?)
 }
 
 pub fn status_router(state: StatusHandler) -> Result<Router, Error> {
     Ok(Router::new()
         .route("/metrics", get(metrics))
         .route("/readyz", get(readyz))
+        .route("/livez", get(livez))
         .with_state(Arc::new(state)))
 }
 
@@ -72,6 +130,10 @@ async fn readyz(State(handler): State<Arc<StatusHandler>>) -> impl IntoResponse
     handler.ready()
 }
 
+async fn livez(State(handler): State<Arc<StatusHandler>>) -> impl IntoResponse {
+    handler.live()
+}
+
 async fn metrics(State(handler): State<Arc<StatusHandler>>) -> String {
     handler.metrics().await
 }
@@ -1,3 +1,4 @@
+use axum::extract::MatchedPath;
 use http::{Request, Response};
 use http_body::Body;
 use pin_project_lite::pin_project;
@@ -5,6 +6,7 @@ use std::{
     future::Future,
     pin::Pin,
     task::{ready, Context, Poll},
+    time::Instant,
 };
 use tower_layer::Layer;
 use tower_service::Service;
@@ -47,17 +49,47 @@ where
         let uri = req.uri();
         let path = uri.path().to_owned();
         metrics::counter!("requests_count", "path" => path).increment(1);
+        // use the route template rather than the raw path to bound label cardinality
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|p| p.as_str().to_owned())
+            .unwrap_or_else(|| "unmatched".to_owned());
         ResponseFuture {
+            in_flight: InFlightGuard::new(route.clone()),
+            route,
+            start: Instant::now(),
             inner: self.inner.call(req),
         }
     }
 }
 
+// Tracks a request in the in flight gauge until dropped
+struct InFlightGuard {
+    route: String,
+}
+
+impl InFlightGuard {
+    fn new(route: String) -> Self {
+        metrics::gauge!("http_requests_in_flight", "route" => route.clone()).increment(1);
+        Self { route }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        metrics::gauge!("http_requests_in_flight", "route" => self.route.clone()).decrement(1);
+    }
+}
+
 pin_project! {
-    /// Response future for [`InFlightRequests`].
+    /// Response future for [`MetricsInterceptor`].
     pub struct ResponseFuture<F> {
         #[pin]
         inner: F,
+        route: String,
+        start: Instant,
+        in_flight: InFlightGuard,
     }
 }
 
@@ -69,15 +101,25 @@ where
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
-        let response = ready!(this.inner.poll(cx))?;
-        let response = response.map(move |body| ResponseBody { inner: body });
+        let result = ready!(this.inner.poll(cx));
+        let status = match result {
+            Ok(ref response) => response.status().as_u16().to_string(),
+            Err(_) => "error".to_owned(),
+        };
+        metrics::histogram!(
+            "http_request_duration_seconds",
+            "route" => this.route.clone(),
+            "status" => status
+        )
+        .record(this.start.elapsed().as_secs_f64());
+        let response = result?.map(move |body| ResponseBody { inner: body });
 
         Poll::Ready(Ok(response))
     }
 }
 
 pin_project! {
-    /// Response body for [`InFlightRequests`].
+    /// Response body for [`MetricsInterceptor`].
     pub struct ResponseBody<B> {
         #[pin]
         inner: B,
@@ -1,36 +1,133 @@
 use std::time::Duration;
 
 use axum::error_handling::HandleErrorLayer;
+use axum::extract::DefaultBodyLimit;
 use axum::Router;
-use http::StatusCode;
+use http::{Request, StatusCode};
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::load_shed::error::Overloaded;
+use tower::timeout::error::Elapsed;
 use tower::timeout::TimeoutLayer;
 use tower::BoxError;
-use tower_http::trace::{
-    DefaultMakeSpan, DefaultOnFailure, DefaultOnRequest, DefaultOnResponse, TraceLayer,
-};
-use tracing::Level;
+use tower_http::trace::{DefaultOnFailure, DefaultOnRequest, DefaultOnResponse, TraceLayer};
+use tracing::{Level, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
+use crate::config::ServerConfig;
 use crate::http::middleware::metrics::MetricsLayer;
+use crate::telemetry::extract_context;
 
 pub(crate) mod metrics;
 
-pub(crate) fn add_default_middleware(router: Router) -> Router {
+pub(crate) fn add_default_middleware(router: Router, cfg: &ServerConfig) -> Router {
     let trace_layer = TraceLayer::new_for_http()
         .on_request(DefaultOnRequest::new().level(Level::INFO))
-        .make_span_with(
-            DefaultMakeSpan::new()
-                .level(Level::INFO)
-                .include_headers(false),
-        )
+        .make_span_with(make_span)
         .on_response(DefaultOnResponse::new().level(Level::INFO))
         .on_failure(DefaultOnFailure::new().level(Level::ERROR));
 
+    // the limit is shared by all routes as router layers are applied per route
+    let concurrency_limit = (cfg.max_concurrent_requests > 0)
+        .then(|| GlobalConcurrencyLimitLayer::new(cfg.max_concurrent_requests));
+
     let layer = tower::ServiceBuilder::new()
         .layer(MetricsLayer::new())
-        .layer(HandleErrorLayer::new(|_: BoxError| async {
-            StatusCode::REQUEST_TIMEOUT
-        }))
-        .layer(TimeoutLayer::new(Duration::from_secs(10)))
+        .layer(HandleErrorLayer::new(handle_error))
+        .load_shed()
+        .option_layer(concurrency_limit)
+        .layer(TimeoutLayer::new(Duration::from_secs(cfg.request_timeout)))
         .layer(trace_layer);
-    router.layer(layer)
+    router
+        .layer(layer)
+        .layer(DefaultBodyLimit::max(cfg.max_body_size))
+}
+
+async fn handle_error(err: BoxError) -> StatusCode {
+    if err.is::<Overloaded>() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else if err.is::<Elapsed>() {
+        StatusCode::REQUEST_TIMEOUT
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+// Same fields as tower_http's DefaultMakeSpan, continuing the caller's trace when a
+// traceparent header is present
+fn make_span<B>(request: &Request<B>) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+    );
+    // only fails when no OpenTelemetry layer is installed
+    let _ = span.set_parent(extract_context(request.headers()));
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::routing::{get, post};
+    use std::sync::Arc;
+    use tokio::sync::Notify;
+    use tower::ServiceExt;
+
+    fn server_config() -> ServerConfig {
+        ServerConfig {
+            request_timeout: 10,
+            max_header_size: 8192,
+            max_body_size: 16,
+            max_concurrent_requests: 1,
+            http_keep_alive: true,
+            shutdown_drain_timeout: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_large_bodies() {
+        let router = add_default_middleware(
+            Router::new().route("/", post(|body: String| async move { body })),
+            &server_config(),
+        );
+        let status = |body: String| {
+            let router = router.clone();
+            async move {
+                router
+                    .oneshot(Request::post("/").body(Body::from(body)).unwrap())
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+        assert_eq!(status("x".repeat(16)).await, StatusCode::OK);
+        assert_eq!(status("x".repeat(17)).await, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn sheds_load_over_limit() {
+        let started = Arc::new(Notify::new());
+        let release = Arc::new(Notify::new());
+        let handler = {
+            let started = started.clone();
+            let release = release.clone();
+            move || async move {
+                started.notify_one();
+                release.notified().await;
+            }
+        };
+        let router =
+            add_default_middleware(Router::new().route("/", get(handler)), &server_config());
+        let request = || Request::get("/").body(Body::empty()).unwrap();
+
+        let first = tokio::spawn(router.clone().oneshot(request()));
+        started.notified().await;
+        let second = router.clone().oneshot(request()).await.unwrap();
+        assert_eq!(second.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        release.notify_one();
+        assert_eq!(first.await.unwrap().unwrap().status(), StatusCode::OK);
+    }
 }
@@ -1,12 +1,23 @@
 mod agent;
+mod debug;
+mod health;
 mod mappings;
 mod metrics;
 mod middleware;
+mod proxy;
+mod tls;
+mod unix;
 mod util;
 mod webhook;
 
 use anyhow::anyhow;
 use anyhow::Error;
+use axum_server::Address;
+use debug::{debug_router, DebugState};
+use health::HealthChecks;
+use health::Heartbeat;
+use hyper_util::rt::TokioExecutor;
+use hyper_util::server::conn::auto::Builder as HttpBuilder;
 use metrics::status_router;
 use metrics::StatusHandler;
 use std::sync::Arc;
@@ -21,46 +32,111 @@ use tracing::info;
 
 use crate::config::AgentConfig;
 use crate::config::CommonConfig;
+use crate::config::DiagnosticsFormat;
+use crate::config::ProxyConfig;
+use crate::config::ServerConfig;
+use crate::config::ValidateMappingsConfig;
 use crate::config::WebhookConfig;
 
 pub async fn serve_agent(cfg: Arc<AgentConfig>) -> Result<(), Error> {
     install_crypto()?;
+    let health_checks = HealthChecks::default();
+    let debug_state = DebugState::default();
     let agent_cancel = CancellationToken::new();
     let agent_handle = tokio::spawn({
         let cfg = cfg.clone();
         let cancel = agent_cancel.clone();
-        async move { agent::start_agent(cancel, cfg).await }
+        let health_checks = health_checks.clone();
+        let debug_state = debug_state.clone();
+        async move { agent::start_agent(cancel, cfg, health_checks, debug_state).await }
     });
-    serve(&cfg.common_config, agent_handle, agent_cancel).await
+    serve(
+        &cfg.common_config,
+        agent_handle,
+        agent_cancel,
+        health_checks,
+        debug_state,
+    )
+    .await
 }
 
 pub async fn serve_webhook(cfg: Arc<WebhookConfig>) -> Result<(), Error> {
     install_crypto()?;
+    let health_checks = HealthChecks::default();
+    let debug_state = DebugState::default();
     let webhook_cancel = CancellationToken::new();
     let webhook_handle = tokio::spawn({
         let cfg = cfg.clone();
         let cancel = webhook_cancel.clone();
-        async move { webhook::start_webhook(cancel, cfg).await }
+        let health_checks = health_checks.clone();
+        let debug_state = debug_state.clone();
+        async move { webhook::start_webhook(cancel, cfg, health_checks, debug_state).await }
     });
-    serve(&cfg.common_config, webhook_handle, webhook_cancel).await
+    serve(
+        &cfg.common_config,
+        webhook_handle,
+        webhook_cancel,
+        health_checks,
+        debug_state,
+    )
+    .await
+}
+
+pub async fn serve_proxy(cfg: Arc<ProxyConfig>) -> Result<(), Error> {
+    proxy::start_proxy(cfg).await
+}
+
+/// Prints the problems in a mapping config, failing when it has errors
+pub async fn validate_mappings(cfg: &ValidateMappingsConfig) -> Result<(), Error> {
+    let path = &cfg.role_mapping_path;
+    let diagnostics = mappings::validate_file(path).await?;
+    match cfg.output {
+        DiagnosticsFormat::Text => {
+            for diagnostic in &diagnostics {
+                println!("{diagnostic}");
+            }
+        }
+        DiagnosticsFormat::Json => println!("{}", serde_json::to_string_pretty(&diagnostics)?),
+    }
+    let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+    if errors > 0 {
+        return Err(anyhow!("{} has {} errors", path.display(), errors));
+    }
+    Ok(())
+}
+
+/// Prints the JSON Schema of mapping files
+pub fn print_mappings_schema() -> Result<(), Error> {
+    println!("{}", mappings::schema()?);
+    Ok(())
 }
 
 async fn serve(
     cfg: &CommonConfig,
     mut server_handle: JoinHandle<Result<(), Error>>,
     server_cancel: CancellationToken,
+    health_checks: HealthChecks,
+    debug_state: DebugState,
 ) -> Result<(), Error> {
     // setup cancellation for graceful shutdown
     let metrics_cancel = CancellationToken::new();
     let metrics_ready = CancellationToken::new();
 
     let metrics_addr = cfg.metrics_address.clone();
+    let status_handler = StatusHandler::try_new(
+        metrics_ready.clone(),
+        health_checks,
+        Heartbeat::start(Duration::from_secs(cfg.liveness_timeout)),
+    )?;
+    let debug = cfg
+        .debug_token_file
+        .clone()
+        .map(|token_file| debug_router(debug_state, token_file));
 
     // start metrics server
     let mut metrics_handle = tokio::spawn({
         let cancel = metrics_cancel.clone();
-        let ready = metrics_ready.clone();
-        async move { start_metrics_server(cancel, ready, &metrics_addr).await }
+        async move { start_metrics_server(cancel, status_handler, debug, &metrics_addr).await }
     });
 
     let ready_grace = cfg.ready_grace_period;
@@ -75,15 +151,11 @@ async fn serve(
         _ = &mut shutdown_handle => {
                 metrics_ready.cancel();
                 sleep(Duration::new(ready_grace, 0)).await;
-                metrics_cancel.cancel();
+                // keep serving metrics until in flight requests are drained
                 server_cancel.cancel();
-                let (metrics, server) = tokio::join!(metrics_handle, server_handle);
-                if let Err(m) = metrics {
-                    error!("metrics exited with error: {}", m.to_string());
-                }
-                if let Err(s) = server {
-                    error!("server exited with error: {}", s.to_string());
-                }
+                exit("app", server_handle.await);
+                metrics_cancel.cancel();
+                exit("metrics", metrics_handle.await);
             },
     };
 
@@ -92,19 +164,49 @@ async fn serve(
 
 async fn start_metrics_server(
     cancel: CancellationToken,
-    ready: CancellationToken,
+    status_handler: StatusHandler,
+    debug: Option<axum::Router>,
     addr: &str,
 ) -> Result<(), Error> {
     let listener = TcpListener::bind(addr).await?;
     info!("metrics listening on {}", addr);
 
-    let app = status_router(StatusHandler::try_new(ready)?)?;
+    let mut app = status_router(status_handler)?;
+    if let Some(debug) = debug {
+        info!("debug routes enabled on metrics listener");
+        app = app.merge(debug);
+    }
     axum::serve(listener, app)
         .with_graceful_shutdown(shutdown_server(cancel))
         .await?;
     Ok(())
 }
 
+/// Applies the connection options shared by the agent and webhook listeners
+fn configure_http(builder: &mut HttpBuilder<TokioExecutor>, cfg: &ServerConfig) {
+    builder
+        .http1()
+        .keep_alive(cfg.http_keep_alive)
+        .max_buf_size(cfg.max_header_size as usize);
+    builder.http2().max_header_list_size(cfg.max_header_size);
+}
+
+/// Gracefully shuts the server down once cancelled. Connections still busy after
+/// the drain timeout are closed.
+async fn drain_on_cancel<A: Address>(
+    cancel: CancellationToken,
+    handle: axum_server::Handle<A>,
+    drain: Duration,
+) {
+    cancel.cancelled().await;
+    info!(
+        "draining {} connections for up to {}s",
+        handle.connection_count(),
+        drain.as_secs()
+    );
+    handle.graceful_shutdown(Some(drain));
+}
+
 fn exit(task: &str, out: Result<Result<(), Error>, JoinError>) {
     match out {
         Ok(Ok(_)) => {
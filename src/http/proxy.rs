@@ -0,0 +1,77 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Error;
+use tokio::io::copy_bidirectional;
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tracing::{debug, error, info};
+
+use crate::config::ProxyConfig;
+
+/// Forwards connections accepted on the loopback listener to the agent's Unix socket
+/// until a shutdown signal is received
+pub(crate) async fn start_proxy(cfg: Arc<ProxyConfig>) -> Result<(), Error> {
+    let listener = TcpListener::bind(&cfg.listen_address).await?;
+    info!(
+        "proxy listening on {}, forwarding to {:?}",
+        cfg.listen_address, cfg.socket_path
+    );
+    let shutdown = super::shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                debug!("proxying connection from {}", peer);
+                tokio::spawn(forward(stream, cfg.socket_path.clone()));
+            }
+            _ = &mut shutdown => return Ok(()),
+        }
+    }
+}
+
+async fn forward(mut stream: TcpStream, socket_path: PathBuf) {
+    if let Err(e) = copy_to_socket(&mut stream, &socket_path).await {
+        error!("failed to proxy connection to {:?}: {}", socket_path, e);
+    }
+}
+
+async fn copy_to_socket(stream: &mut TcpStream, socket_path: &Path) -> std::io::Result<()> {
+    let mut upstream = UnixStream::connect(socket_path).await?;
+    copy_bidirectional(stream, &mut upstream).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixListener;
+
+    #[tokio::test]
+    async fn forwards_to_socket() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let socket_path = dir.join("agent.sock");
+        let upstream = UnixListener::bind(&socket_path).unwrap();
+        tokio::spawn(async move {
+            let (mut conn, _) = upstream.accept().await.unwrap();
+            let mut buf = [0; 4];
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(&buf).await.unwrap();
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            forward(stream, socket_path).await;
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
@@ -0,0 +1,186 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Error};
+use axum_server::tls_rustls::RustlsConfig;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{server::WebPkiClientVerifier, ServerConfig as RustlsServerConfig};
+use tracing::info;
+
+use super::health::{CheckStatus, ReadinessCheck};
+use super::util::ReloadableFiles;
+
+/// Copies the CA bundle that issued the serving certificate to a location pods can
+/// mount so clients can verify the certificate
+#[derive(Clone, Debug)]
+pub(crate) struct CaExport {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+}
+
+impl CaExport {
+    // written to a temporary file first so readers never see a partial bundle
+    pub(crate) async fn export(&self) -> Result<(), Error> {
+        let bundle = tokio::fs::read(&self.source).await?;
+        let mut tmp = self.destination.clone().into_os_string();
+        tmp.push(".tmp");
+        if let Some(parent) = self.destination.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&tmp, bundle).await?;
+        tokio::fs::rename(&tmp, &self.destination).await?;
+        info!("exported CA bundle to {:?}", self.destination);
+        Ok(())
+    }
+}
+
+/// Reloads the serving certificate whenever the certificate or key changes, exporting
+/// the CA bundle again if one is exported
+pub(crate) async fn start_tls_watch(
+    tls_config: RustlsConfig,
+    cert: PathBuf,
+    key: PathBuf,
+    cert_check: CertificateCheck,
+    ca_export: Option<CaExport>,
+) {
+    let mut paths = vec![cert.clone(), key.clone()];
+    paths.extend(ca_export.iter().map(|ca_export| ca_export.source.clone()));
+    ReloadableFiles::new("tls_certificate", paths)
+        .watch(|| async {
+            reload_tls(&tls_config, &cert, &key, &cert_check).await?;
+            if let Some(ref ca_export) = ca_export {
+                ca_export.export().await?;
+            }
+            Ok::<_, Error>(())
+        })
+        .await
+}
+
+async fn reload_tls(
+    config: &RustlsConfig,
+    cert: impl AsRef<Path>,
+    key: impl AsRef<Path>,
+    cert_check: &CertificateCheck,
+) -> Result<(), Error> {
+    config.reload_from_pem_file(&cert, key).await?;
+    info!("successfully reloaded tls config");
+    cert_check.update(&cert)
+}
+
+/// Readiness check reporting whether the served certificate is loaded and unexpired
+#[derive(Clone)]
+pub(crate) struct CertificateCheck {
+    not_after: Arc<RwLock<i64>>,
+}
+
+impl CertificateCheck {
+    pub(crate) fn try_new(cert: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(Self {
+            not_after: Arc::new(RwLock::new(cert_not_after(cert)?)),
+        })
+    }
+
+    fn update(&self, cert: impl AsRef<Path>) -> Result<(), Error> {
+        let not_after = cert_not_after(cert)?;
+        *self
+            .not_after
+            .write()
+            .expect("certificate check lock poisoned") = not_after;
+        Ok(())
+    }
+}
+
+impl ReadinessCheck for CertificateCheck {
+    fn name(&self) -> &'static str {
+        "tls_certificate"
+    }
+
+    fn status(&self) -> CheckStatus {
+        let not_after = *self
+            .not_after
+            .read()
+            .expect("certificate check lock poisoned");
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        if now < not_after {
+            CheckStatus::ready(format!("certificate expires in {}s", not_after - now))
+        } else {
+            CheckStatus::not_ready("certificate expired")
+        }
+    }
+}
+
+// Returns the expiry of the leaf certificate as seconds since the unix epoch
+fn cert_not_after(cert: impl AsRef<Path>) -> Result<i64, Error> {
+    let certs = load_certs(cert)?;
+    let leaf = certs
+        .first()
+        .ok_or_else(|| anyhow!("no certificate found"))?;
+    let (_, parsed) = x509_parser::parse_x509_certificate(leaf)
+        .map_err(|e| anyhow!("failed to parse certificate: {}", e))?;
+    Ok(parsed.validity().not_after.timestamp())
+}
+
+pub(crate) fn create_tls_config(
+    cert: impl AsRef<Path>,
+    priv_key: impl AsRef<Path>,
+) -> Result<RustlsConfig, Error> {
+    let cert_chain = load_certs(cert)?;
+    let key_der = load_private_key(priv_key)?;
+    let mut tls_config = RustlsServerConfig::builder()
+        .with_client_cert_verifier(WebPkiClientVerifier::no_client_auth())
+        .with_single_cert(cert_chain, key_der)?;
+    tls_config
+        .alpn_protocols
+        .append(&mut vec!["http/1.1".into()]);
+
+    Ok(RustlsConfig::from_config(Arc::new(tls_config)))
+}
+
+fn load_private_key(priv_key: impl AsRef<Path>) -> Result<PrivateKeyDer<'static>, Error> {
+    Ok(PrivateKeyDer::from_pem_file(priv_key)?)
+}
+
+fn load_certs(server_cert: impl AsRef<Path>) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs: Vec<_> = CertificateDer::pem_file_iter(server_cert)?.collect();
+
+    let mut server_certs = vec![];
+    for cert in certs {
+        match cert {
+            Ok(c) => server_certs.push(c),
+            Err(e) => return Err(anyhow!("failed to parse server ca: {}", e)),
+        }
+    }
+    Ok(server_certs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn exports_ca_bundle() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let source = dir.join("ca.crt");
+        tokio::fs::write(&source, "first").await.unwrap();
+        let export = CaExport {
+            source: source.clone(),
+            destination: dir.join("host").join("ca.crt"),
+        };
+
+        export.export().await.unwrap();
+        tokio::fs::write(&source, "second").await.unwrap();
+        export.export().await.unwrap();
+        assert_eq!(
+            tokio::fs::read_to_string(&export.destination)
+                .await
+                .unwrap(),
+            "second"
+        );
+    }
+}
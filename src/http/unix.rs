@@ -0,0 +1,64 @@
+use std::fs::Permissions;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+
+use tracing::info;
+
+use crate::config::UnixSocketConfig;
+use crate::error::Error;
+
+/// Binds the Unix socket at `path`, replacing a socket left behind by a previous run,
+/// and applies the configured permissions and owner
+pub(crate) fn bind_unix_socket(path: &Path, cfg: &UnixSocketConfig) -> Result<UnixListener, Error> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            info!("removing stale socket {:?}", path);
+            std::fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(Error::OtherError(format!(
+                "{path:?} exists and is not a socket"
+            )))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+    std::fs::set_permissions(path, Permissions::from_mode(cfg.unix_socket_mode))?;
+    if let Some(owner) = cfg.unix_socket_owner {
+        std::os::unix::fs::chown(path, Some(owner.uid), owner.gid)?;
+    }
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_stale_socket() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("agent.sock");
+        let cfg = UnixSocketConfig {
+            unix_socket_path: Some(path.clone()),
+            unix_socket_mode: 0o660,
+            unix_socket_owner: None,
+        };
+
+        let first = bind_unix_socket(&path, &cfg).unwrap();
+        drop(first);
+        let _second = bind_unix_socket(&path, &cfg).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(bind_unix_socket(&path, &cfg).is_err());
+    }
+}
@@ -1,11 +1,250 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use crate::error::Error;
-use notify::{Event, RecommendedWatcher, Watcher};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use sha2::{Digest, Sha256};
 use tokio::sync::mpsc::{channel, Receiver};
-use tracing::error;
+use tokio::time::{timeout, Instant, MissedTickBehavior};
+use tracing::{debug, error, info, warn};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+type EventReceiver = Receiver<notify::Result<Event>>;
+
+/// Config files mounted from a ConfigMap or Secret that are reloaded when their contents
+/// change. Kubernetes updates these mounts by swapping the `..data` symlink in their
+/// directory, so the parent directories are watched rather than the files themselves.
+/// Paths may also be directories, which change when any file in them changes or files
+/// are added or removed.
+/// Events are debounced and the contents hashed so only actual changes cause a reload,
+/// and the files are polled as well in case events are missed or the watcher fails.
+/// Failed reloads are retried by the poll, as files can be observed half-written.
+#[derive(Debug, Clone)]
+pub(crate) struct ReloadableFiles {
+    name: &'static str,
+    paths: Vec<PathBuf>,
+    debounce: Duration,
+    /// Longest a reload is delayed by events that keep arriving, e.g. from unrelated
+    /// files in a watched directory
+    max_debounce: Duration,
+    poll_interval: Duration,
+}
+
+enum Wake {
+    Poll,
+    Event(Option<notify::Result<Event>>),
+}
+
+impl ReloadableFiles {
+    /// `name` identifies the files in logs and the `config` label of the metrics
+    pub(crate) fn new(name: &'static str, paths: Vec<PathBuf>) -> Self {
+        Self {
+            name,
+            paths,
+            debounce: Duration::from_millis(500),
+            max_debounce: Duration::from_secs(5),
+            poll_interval: Duration::from_secs(30),
+        }
+    }
+
+    #[cfg(test)]
+    fn with_intervals(mut self, debounce: Duration, poll_interval: Duration) -> Self {
+        self.debounce = debounce;
+        self.max_debounce = debounce * 10;
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Calls `reload` whenever the contents of the files change, which assumes the
+    /// current contents have already been loaded. Runs until the task is dropped.
+    pub(crate) async fn watch<F, Fut, E>(self, mut reload: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+        E: Display,
+    {
+        let mut hash = self.hash().await.ok();
+        metrics::gauge!("config_last_reload_timestamp_seconds", "config" => self.name)
+            .set(unix_now());
+        let mut watcher: Option<(RecommendedWatcher, EventReceiver)> = None;
+        let mut backoff = MIN_BACKOFF;
+        let mut retry_at = Instant::now();
+        let mut poll = tokio::time::interval(self.poll_interval);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            if watcher.is_none() && Instant::now() >= retry_at {
+                match self.create_watcher() {
+                    Ok(created) => {
+                        debug!("watching {} files", self.name);
+                        watcher = Some(created);
+                        backoff = MIN_BACKOFF;
+                    }
+                    Err(e) => {
+                        error!("failed to watch {} files, polling only: {}", self.name, e);
+                        metrics::counter!("config_watch_errors", "config" => self.name)
+                            .increment(1);
+                        retry_at = Instant::now() + backoff;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                }
+            }
+            let wake = tokio::select! {
+                _ = poll.tick() => Wake::Poll,
+                event = next_event(&mut watcher) => Wake::Event(event),
+            };
+            match wake {
+                Wake::Poll => {}
+                Wake::Event(Some(Ok(_))) => {
+                    if let Some((_, ref mut rx)) = watcher {
+                        self.settle(rx).await;
+                    }
+                }
+                Wake::Event(Some(Err(e))) => {
+                    warn!("{} watcher error: {}", self.name, e);
+                    metrics::counter!("config_watch_errors", "config" => self.name).increment(1);
+                    continue;
+                }
+                // the watcher stopped, it is recreated after the backoff
+                Wake::Event(None) => {
+                    watcher = None;
+                    retry_at = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            }
+            match self.hash().await {
+                // the hash is only updated once the reload succeeded, so a failed one is
+                // retried even though the contents do not change again
+                Ok(current) if hash.as_ref() != Some(&current) => {
+                    if self.reload(&mut reload).await {
+                        hash = Some(current);
+                    }
+                }
+                Ok(_) => {}
+                // files are briefly missing while they are replaced, the next event or
+                // poll picks up the new contents
+                Err(e) => debug!("failed to read {} files: {}", self.name, e),
+            }
+        }
+    }
+
+    // returns whether the reload succeeded
+    async fn reload<F, Fut, E>(&self, reload: &mut F) -> bool
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+        E: Display,
+    {
+        info!("{} files changed, reloading", self.name);
+        let outcome = match reload().await {
+            Ok(()) => {
+                metrics::gauge!("config_last_reload_timestamp_seconds", "config" => self.name)
+                    .set(unix_now());
+                "success"
+            }
+            Err(e) => {
+                error!("failed to reload {} files: {}", self.name, e);
+                "failure"
+            }
+        };
+        metrics::counter!("config_reloads", "config" => self.name, "outcome" => outcome)
+            .increment(1);
+        outcome == "success"
+    }
+
+    // waits until no events arrived for the debounce interval, an update of a mount
+    // produces several, but no longer than the maximum
+    async fn settle(&self, rx: &mut EventReceiver) {
+        let deadline = Instant::now() + self.max_debounce;
+        loop {
+            let wait = self
+                .debounce
+                .min(deadline.saturating_duration_since(Instant::now()));
+            if wait.is_zero() {
+                return;
+            }
+            let Ok(Some(_)) = timeout(wait, rx.recv()).await else {
+                return;
+            };
+        }
+    }
+
+    fn create_watcher(&self) -> Result<(RecommendedWatcher, EventReceiver), Error> {
+        let (mut watcher, rx) = create_watcher()?;
+        let mut dirs: Vec<PathBuf> = self
+            .paths
+            .iter()
+            .filter_map(|path| path.parent())
+            .map(|parent| {
+                if parent.as_os_str().is_empty() {
+                    PathBuf::from(".")
+                } else {
+                    parent.to_path_buf()
+                }
+            })
+            .chain(self.paths.iter().filter(|path| path.is_dir()).cloned())
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        for dir in dirs {
+            watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        }
+        Ok((watcher, rx))
+    }
+
+    // reading follows the symlinks so the hash covers the current contents
+    async fn hash(&self) -> Result<String, Error> {
+        let mut hasher = Sha256::new();
+        for path in &self.paths {
+            if !tokio::fs::metadata(path).await?.is_dir() {
+                hasher.update(tokio::fs::read(path).await?);
+                continue;
+            }
+            for file in dir_files(path).await? {
+                hasher.update(file.as_os_str().as_encoded_bytes());
+                hasher.update(tokio::fs::read(&file).await?);
+            }
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+}
+
+// files in a directory sorted by name, skipping hidden ones like the `..data` symlink
+async fn dir_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut files = vec![];
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if !hidden && tokio::fs::metadata(entry.path()).await?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+async fn next_event(
+    watcher: &mut Option<(RecommendedWatcher, EventReceiver)>,
+) -> Option<notify::Result<Event>> {
+    match watcher {
+        Some((_, rx)) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
 
-pub(crate) fn create_watcher(
-) -> Result<(RecommendedWatcher, Receiver<notify::Result<Event>>), Error> {
-    let (tx, rx) = channel(1);
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+fn create_watcher() -> Result<(RecommendedWatcher, EventReceiver), Error> {
+    let (tx, rx) = channel(16);
     let watcher = RecommendedWatcher::new(
         move |res| {
             if let Err(e) = tx.blocking_send(res) {
@@ -17,3 +256,93 @@ pub(crate) fn create_watcher(
 
     Ok((watcher, rx))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // lays out a directory the way the kubelet does for ConfigMap volumes
+    async fn write_version(dir: &std::path::Path, version: &str, contents: &str) {
+        let data = dir.join(version);
+        tokio::fs::create_dir_all(&data).await.unwrap();
+        tokio::fs::write(data.join("config.yaml"), contents)
+            .await
+            .unwrap();
+        let tmp = dir.join("..data_tmp");
+        tokio::fs::symlink(version, &tmp).await.unwrap();
+        tokio::fs::rename(&tmp, dir.join("..data")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloads_on_symlink_swap() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        write_version(dir, "..v1", "first").await;
+        let path = dir.join("config.yaml");
+        tokio::fs::symlink("..data/config.yaml", &path)
+            .await
+            .unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let files = ReloadableFiles::new("test", vec![path.clone()])
+            .with_intervals(Duration::from_millis(50), Duration::from_millis(200));
+        let watch = tokio::spawn(files.watch(move || {
+            let tx = tx.clone();
+            let path = path.clone();
+            async move {
+                let contents = tokio::fs::read_to_string(&path).await?;
+                tx.send(contents).unwrap();
+                Ok::<_, std::io::Error>(())
+            }
+        }));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        write_version(dir, "..v2", "second").await;
+        let reloaded = timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+        assert_eq!(reloaded.as_deref(), Some("second"));
+        // unchanged contents do not cause another reload
+        assert!(timeout(Duration::from_millis(500), rx.recv())
+            .await
+            .is_err());
+
+        watch.abort();
+    }
+
+    #[tokio::test]
+    async fn retries_failed_reload() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("config.yaml");
+        tokio::fs::write(&path, "first").await.unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let files = ReloadableFiles::new("test", vec![path.clone()])
+            .with_intervals(Duration::from_millis(50), Duration::from_millis(200));
+        let mut attempts = 0;
+        let watch = tokio::spawn(files.watch(move || {
+            attempts += 1;
+            let attempt = attempts;
+            let tx = tx.clone();
+            async move {
+                tx.send(attempt).unwrap();
+                match attempt {
+                    1 => Err("half-written"),
+                    _ => Ok(()),
+                }
+            }
+        }));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        tokio::fs::write(&path, "second").await.unwrap();
+        for attempt in 1..=2 {
+            let reloaded = timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+            assert_eq!(reloaded, Some(attempt));
+        }
+        // the successful reload is not repeated
+        assert!(timeout(Duration::from_millis(500), rx.recv())
+            .await
+            .is_err());
+
+        watch.abort();
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::BTreeSet;
+
+use k8s_openapi::api::core::v1::Pod;
+
+const PREFIX: &str = "homelab-aws-creds.io/";
+/// `"false"` skips injecting the pod
+const INJECT: &str = "homelab-aws-creds.io/inject";
+/// Comma separated names of the only containers that are injected
+const INJECT_CONTAINERS: &str = "homelab-aws-creds.io/inject-containers";
+/// Comma separated names of containers that are not injected
+const SKIP_CONTAINERS: &str = "homelab-aws-creds.io/skip-containers";
+/// Region injected instead of the webhook's region
+const REGION: &str = "homelab-aws-creds.io/region";
+
+/// What a pod changes about its injection with annotations. Invalid annotations are
+/// ignored with a warning, so a typo never keeps a pod from being created.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct PodAnnotations {
+    pub skip: bool,
+    pub inject_containers: Option<Vec<String>>,
+    pub skip_containers: Vec<String>,
+    pub region: Option<String>,
+}
+
+impl PodAnnotations {
+    /// Parses the annotations of the pod, returning warnings for the ones that are invalid
+    pub(crate) fn parse(pod: &Pod) -> (Self, Vec<String>) {
+        let mut annotations = Self::default();
+        let mut warnings = vec![];
+        let containers = container_names(pod);
+        for (key, value) in pod.metadata.annotations.iter().flatten() {
+            if !key.starts_with(PREFIX) {
+                continue;
+            }
+            let result = match key.as_str() {
+                INJECT => match value.as_str() {
+                    "true" => Ok(()),
+                    "false" => {
+                        annotations.skip = true;
+                        Ok(())
+                    }
+                    _ => Err(format!("{value} is not true or false, the pod is injected")),
+                },
+                INJECT_CONTAINERS => {
+                    let names = container_list(value);
+                    let unknown = unknown_containers(&names, &containers);
+                    annotations.inject_containers = Some(names);
+                    unknown
+                }
+                SKIP_CONTAINERS => {
+                    annotations.skip_containers = container_list(value);
+                    unknown_containers(&annotations.skip_containers, &containers)
+                }
+                REGION => region(value).map(|()| annotations.region = Some(value.clone())),
+                _ => Err("unknown annotation".into()),
+            };
+            if let Err(e) = result {
+                warnings.push(format!("annotation {key}: {e}"));
+            }
+        }
+        (annotations, warnings)
+    }
+
+    /// Whether the container is injected, skipping it wins over listing it
+    pub(crate) fn injects(&self, container: &str) -> bool {
+        let listed = self
+            .inject_containers
+            .as_ref()
+            .is_none_or(|names| names.iter().any(|name| name == container));
+        listed && !self.skip_containers.iter().any(|name| name == container)
+    }
+}
+
+fn container_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn container_names(pod: &Pod) -> BTreeSet<&str> {
+    let Some(ref spec) = pod.spec else {
+        return BTreeSet::new();
+    };
+    spec.init_containers
+        .iter()
+        .flatten()
+        .chain(&spec.containers)
+        .map(|container| container.name.as_str())
+        .chain(
+            spec.ephemeral_containers
+                .iter()
+                .flatten()
+                .map(|container| container.name.as_str()),
+        )
+        .collect()
+}
+
+// names that match no container are most likely typos
+fn unknown_containers(names: &[String], containers: &BTreeSet<&str>) -> Result<(), String> {
+    let unknown: Vec<&str> = names
+        .iter()
+        .map(String::as_str)
+        .filter(|name| !containers.contains(name))
+        .collect();
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("no container named {}", unknown.join(", ")))
+    }
+}
+
+// Regions are lowercase words separated by '-' ending in a number, like us-gov-west-1
+fn region(value: &str) -> Result<(), String> {
+    let parts: Vec<&str> = value.split('-').collect();
+    let valid = parts.len() >= 3
+        && parts.iter().all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        })
+        && parts
+            .last()
+            .is_some_and(|part| part.chars().all(|c| c.is_ascii_digit()));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("{value} is not a region, the default is injected"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use k8s_openapi::api::core::v1::{Container, PodSpec};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    use super::*;
+
+    fn pod(annotations: &[(&str, &str)]) -> Pod {
+        let container = |name: &str| Container {
+            name: name.into(),
+            ..Default::default()
+        };
+        Pod {
+            metadata: ObjectMeta {
+                annotations: Some(BTreeMap::from_iter(
+                    annotations
+                        .iter()
+                        .map(|(key, value)| (key.to_string(), value.to_string())),
+                )),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                containers: vec![container("app"), container("log-shipper")],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_annotations() {
+        let (annotations, warnings) = PodAnnotations::parse(&pod(&[
+            (INJECT_CONTAINERS, "app, log-shipper"),
+            (SKIP_CONTAINERS, "log-shipper"),
+            (REGION, "us-gov-west-1"),
+            ("example.com/inject", "maybe"),
+        ]));
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert!(!annotations.skip);
+        assert!(annotations.injects("app"));
+        assert!(!annotations.injects("log-shipper"));
+        assert_eq!(annotations.region.as_deref(), Some("us-gov-west-1"));
+
+        let (annotations, warnings) = PodAnnotations::parse(&pod(&[(INJECT, "false")]));
+        assert!(annotations.skip && warnings.is_empty());
+    }
+
+    #[test]
+    fn warns_on_invalid_annotations() {
+        let (annotations, warnings) = PodAnnotations::parse(&pod(&[
+            (INJECT, "no"),
+            (INJECT_CONTAINERS, "app,ap"),
+            (REGION, "us_west_2"),
+            ("homelab-aws-creds.io/role", "admin"),
+        ]));
+        assert_eq!(
+            annotations,
+            PodAnnotations {
+                inject_containers: Some(vec!["app".into(), "ap".into()]),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            warnings,
+            [
+                "annotation homelab-aws-creds.io/inject: no is not true or false, the pod is injected",
+                "annotation homelab-aws-creds.io/inject-containers: no container named ap",
+                "annotation homelab-aws-creds.io/region: us_west_2 is not a region, the default is injected",
+                "annotation homelab-aws-creds.io/role: unknown annotation",
+            ]
+        );
+    }
+}
@@ -1,138 +1,69 @@
+mod annotations;
 mod patch;
 mod state;
 
 use std::net::SocketAddr;
-use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::config::WebhookConfig;
-use crate::http::mappings;
-use anyhow::{anyhow, Error};
-use axum_server::tls_rustls::RustlsConfig;
-use notify::{RecursiveMode, Watcher};
+use crate::http::debug::DebugState;
+use crate::http::health::HealthChecks;
+use crate::http::tls::{create_tls_config, start_tls_watch, CertificateCheck};
+use crate::http::{configure_http, drain_on_cancel, mappings};
+use anyhow::Error;
+use patch::PatchConfig;
 use state::{new_webhook_router, WebhookState};
-use tokio::select;
-use tokio::task::JoinHandle;
-use tokio_rustls::rustls::pki_types::pem::PemObject;
-use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use tokio_rustls::rustls::{server::WebPkiClientVerifier, ServerConfig as RustlsServerConfig};
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info};
-
-use super::util::create_watcher;
+use tracing::info;
 
 pub(crate) async fn start_webhook(
     cancel: CancellationToken,
     cfg: Arc<WebhookConfig>,
+    health_checks: HealthChecks,
+    debug_state: DebugState,
 ) -> Result<(), Error> {
     let role_mappings =
         mappings::Mapping::try_new_from_file(cfg.common_config.role_mapping_path.clone()).await?;
-    let router = new_webhook_router(WebhookState::new(
-        role_mappings,
-        cfg.agent_address.clone(),
-        cfg.aws_region.clone(),
-    ));
+    health_checks.register(role_mappings.readiness_check());
+    if cfg.common_config.watches_cluster() {
+        let client = kube::Client::try_default().await?;
+        role_mappings.watch_cluster(client, &cfg.common_config, &health_checks);
+    }
+    debug_state.set_mappings(role_mappings.clone());
+    let router = new_webhook_router(
+        WebhookState::new(role_mappings, PatchConfig::new(&cfg)),
+        &cfg.common_config.server_config,
+    );
     let cert = cfg.cert.clone();
     let key = cfg.key.clone();
     let tls_config = create_tls_config(&cert, &key)?;
+    let cert_check = CertificateCheck::try_new(&cert)?;
+    health_checks.register(cert_check.clone());
+    health_checks.initialised();
 
-    tokio::spawn(start_tls_watch(tls_config.clone(), cert, key));
+    tokio::spawn(start_tls_watch(
+        tls_config.clone(),
+        cert,
+        key,
+        cert_check,
+        None,
+    ));
 
     let server_address: SocketAddr = cfg.server_address.parse()?;
     let handle = axum_server::Handle::new();
-    let shutdown_handle = handle.clone();
-    let h: JoinHandle<Result<(), Error>> = tokio::spawn(async move {
-        info!(
-            "webhook configured to listen securely on {}",
-            server_address
-        );
-        axum_server::tls_rustls::bind_rustls(server_address, tls_config)
-            .handle(handle)
-            .serve(router.into_make_service())
-            .await?;
-        Ok(())
-    });
-    select! {
-        h = h => {
-                match h {
-                    Ok(Err(e)) => return Err(e),
-                    Ok(Ok(_)) => {}
-                    Err(_) => return Err(anyhow!("join handle failure")),
-                }
-            },
-        _  = cancel.cancelled() => {}
-    }
-    shutdown_handle.graceful_shutdown(None);
+    tokio::spawn(drain_on_cancel(
+        cancel,
+        handle.clone(),
+        Duration::from_secs(cfg.common_config.server_config.shutdown_drain_timeout),
+    ));
+    let mut server =
+        axum_server::tls_rustls::bind_rustls(server_address, tls_config).handle(handle);
+    configure_http(server.http_builder(), &cfg.common_config.server_config);
+    info!(
+        "webhook configured to listen securely on {}",
+        server_address
+    );
+    server.serve(router.into_make_service()).await?;
     Ok(())
 }
-
-async fn start_tls_watch(tls_config: RustlsConfig, cert: PathBuf, key: PathBuf) {
-    loop {
-        info!("starting TLS watcher");
-        let Ok((mut watcher, mut rx)) = create_watcher() else {
-            error!("failed to create watcher");
-            continue;
-        };
-        if watcher.watch(&cert, RecursiveMode::Recursive).is_ok() {
-            while let Some(res) = rx.recv().await {
-                match res {
-                    Ok(event) => {
-                        match event.kind {
-                            notify::EventKind::Modify(_) => {
-                                reload_tls(&tls_config, &cert, &key).await
-                            }
-                            notify::EventKind::Remove(_) => {
-                                break;
-                            }
-                            _ => {}
-                        }
-                        if event.kind.is_modify() {}
-                    }
-                    Err(e) => error!("watcher error: {}", e),
-                }
-            }
-        } else {
-            error!("failed to watch path: {:?}", cert);
-        }
-    }
-}
-
-async fn reload_tls(config: &RustlsConfig, cert: impl AsRef<Path>, key: impl AsRef<Path>) {
-    match config.reload_from_pem_file(cert, key).await {
-        Ok(_) => info!("successfully reloaded tls config"),
-        Err(e) => error!("failed to reload tls config: {}", e),
-    }
-}
-
-fn create_tls_config(
-    cert: impl AsRef<Path>,
-    priv_key: impl AsRef<Path>,
-) -> Result<RustlsConfig, Error> {
-    let cert_chain = load_certs(cert)?;
-    let key_der = load_private_key(priv_key)?;
-    let mut tls_config = RustlsServerConfig::builder()
-        .with_client_cert_verifier(WebPkiClientVerifier::no_client_auth())
-        .with_single_cert(cert_chain, key_der)?;
-    tls_config
-        .alpn_protocols
-        .append(&mut vec!["http/1.1".into()]);
-
-    Ok(RustlsConfig::from_config(Arc::new(tls_config)))
-}
-
-fn load_private_key(priv_key: impl AsRef<Path>) -> Result<PrivateKeyDer<'static>, Error> {
-    Ok(PrivateKeyDer::from_pem_file(priv_key)?)
-}
-
-fn load_certs(server_cert: impl AsRef<Path>) -> Result<Vec<CertificateDer<'static>>, Error> {
-    let certs: Vec<_> = CertificateDer::pem_file_iter(server_cert)?.collect();
-
-    let mut server_certs = vec![];
-    for cert in certs {
-        match cert {
-            Ok(c) => server_certs.push(c),
-            Err(e) => return Err(anyhow!("failed to parse server ca: {}", e)),
-        }
-    }
-    Ok(server_certs)
-}
@@ -1,97 +1,553 @@
-use json_patch::Patch;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use json_patch::{AddOperation, Patch, PatchOperation};
 use jsonptr::PointerBuf;
-use k8s_openapi::api::core::v1::{EnvVar, Pod};
+use k8s_openapi::api::core::v1::{
+    Container, EnvVar, EphemeralContainer, HostPathVolumeSource, Pod, PodSpec,
+    ProjectedVolumeSource, SecurityContext, ServiceAccountTokenProjection, Volume, VolumeMount,
+    VolumeProjection,
+};
+use serde::Serialize;
+
+use super::annotations::PodAnnotations;
+use crate::config::{AgentEndpointMode, WebhookConfig};
 
 const ENV_AWS_FULL_URI: &str = "AWS_CONTAINER_CREDENTIALS_FULL_URI";
 const ENV_AWS_TOKEN_FILE: &str = "AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE";
 const ENV_AWS_DEFAULT_REGION: &str = "AWS_DEFAULT_REGION";
 const ENV_AWS_REGION: &str = "AWS_REGION";
+const ENV_AGENT_SOCKET: &str = "HOMELAB_AWS_CREDS_SOCKET";
+const ENV_AWS_CA_BUNDLE: &str = "AWS_CA_BUNDLE";
+const ENV_NODE_EXTRA_CA_CERTS: &str = "NODE_EXTRA_CA_CERTS";
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
 const TOKEN_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+const PROJECTED_TOKEN_DIR: &str = "/var/run/secrets/homelab-aws-creds";
+const PROJECTED_TOKEN_PATH: &str = "/var/run/secrets/homelab-aws-creds/token";
+const PROJECTED_TOKEN_EXPIRATION: i64 = 3600;
+const CREDENTIALS_PATH: &str = "/v1/container-credentials";
+const AGENT_SOCKET_VOLUME: &str = "homelab-aws-creds-agent";
+const AGENT_CA_VOLUME: &str = "homelab-aws-creds-ca";
+const TOKEN_VOLUME: &str = "homelab-aws-creds-token";
+const PROXY_CONTAINER: &str = "homelab-aws-creds-proxy";
+
+/// Describes how mutated pods are configured to reach the agent
+#[derive(Debug, Clone)]
+pub(crate) struct PatchConfig {
+    pub agent_address: String,
+    pub region: String,
+    pub endpoint: AgentEndpointMode,
+    pub agent_socket_path: PathBuf,
+    pub proxy_image: Option<String>,
+    pub proxy_port: u16,
+    pub agent_tls: bool,
+    pub agent_ca_path: PathBuf,
+    pub aws_ca_bundle: bool,
+}
+
+impl PatchConfig {
+    pub(crate) fn new(cfg: &WebhookConfig) -> Self {
+        Self {
+            agent_address: cfg.agent_address.clone(),
+            region: cfg.aws_region.clone(),
+            endpoint: cfg.agent_endpoint,
+            agent_socket_path: cfg.agent_socket_path.clone(),
+            proxy_image: cfg.proxy_image.clone(),
+            proxy_port: cfg.proxy_port,
+            agent_tls: cfg.agent_tls,
+            agent_ca_path: cfg.agent_ca_path.clone(),
+            aws_ca_bundle: cfg.agent_aws_ca_bundle,
+        }
+    }
+
+    // TLS is only used when pods connect to the agent over the network
+    fn uses_tls(&self) -> bool {
+        self.endpoint == AgentEndpointMode::Tcp && self.agent_tls
+    }
+
+    // base URL of the agent, the socket has no URL
+    fn agent_url(&self) -> Option<String> {
+        match self.endpoint {
+            AgentEndpointMode::Tcp => {
+                let scheme = if self.agent_tls { "https" } else { "http" };
+                Some(format!("{scheme}://{}", self.agent_address))
+            }
+            AgentEndpointMode::Socket => None,
+            AgentEndpointMode::Proxy => Some(format!("http://127.0.0.1:{}", self.proxy_port)),
+        }
+    }
+
+    // environment pointing the container at the agent, followed by a credentials URI
+    // for every named role of the service account
+    fn credential_env(&self, aliases: &[&str], token_path: &str) -> Vec<EnvVar> {
+        let Some(url) = self.agent_url() else {
+            return vec![
+                env_var(
+                    ENV_AGENT_SOCKET,
+                    self.agent_socket_path.to_string_lossy().into_owned(),
+                ),
+                env_var(ENV_AWS_TOKEN_FILE, token_path),
+            ];
+        };
+        let mut env = vec![
+            env_var(ENV_AWS_FULL_URI, format!("{url}{CREDENTIALS_PATH}")),
+            env_var(ENV_AWS_TOKEN_FILE, token_path),
+        ];
+        env.extend(aliases.iter().map(|alias| {
+            env_var(
+                &alias_env_name(alias),
+                format!("{url}{CREDENTIALS_PATH}/{alias}"),
+            )
+        }));
+        env
+    }
+
+    // region of the pod's annotation, or the webhook's
+    fn region_env(&self, annotations: &PodAnnotations) -> Vec<EnvVar> {
+        let region = annotations.region.as_deref().unwrap_or(&self.region);
+        vec![
+            env_var(ENV_AWS_DEFAULT_REGION, region),
+            env_var(ENV_AWS_REGION, region),
+        ]
+    }
+
+    // CA bundle env for the SDKs that do not already have it configured. Node adds
+    // NODE_EXTRA_CA_CERTS to its roots while AWS_CA_BUNDLE replaces them, so the latter
+    // is only set when enabled
+    fn ca_env(&self, env: &[EnvVar]) -> Vec<EnvVar> {
+        let ca_path = self.agent_ca_path.to_string_lossy();
+        let aws_ca_bundle = self.aws_ca_bundle.then_some(ENV_AWS_CA_BUNDLE);
+        [Some(ENV_NODE_EXTRA_CA_CERTS), aws_ca_bundle]
+            .into_iter()
+            .flatten()
+            .filter(|name| !env.iter().any(|nv| nv.name == *name))
+            .map(|name| env_var(name, ca_path.as_ref()))
+            .collect()
+    }
+
+    // the CA's directory is mounted at the same path it has on the host
+    fn ca_dir(&self) -> &Path {
+        self.agent_ca_path.parent().unwrap_or(Path::new("/"))
+    }
+
+    fn ca_volume(&self) -> Volume {
+        Volume {
+            name: AGENT_CA_VOLUME.into(),
+            host_path: Some(HostPathVolumeSource {
+                path: self.ca_dir().to_string_lossy().into_owned(),
+                type_: Some("Directory".into()),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn ca_mount(&self) -> VolumeMount {
+        VolumeMount {
+            name: AGENT_CA_VOLUME.into(),
+            mount_path: self.ca_dir().to_string_lossy().into_owned(),
+            read_only: Some(true),
+            ..Default::default()
+        }
+    }
+
+    // the socket's directory is mounted at the same path it has on the host
+    fn socket_dir(&self) -> &Path {
+        self.agent_socket_path.parent().unwrap_or(Path::new("/"))
+    }
+
+    fn socket_volume(&self) -> Volume {
+        Volume {
+            name: AGENT_SOCKET_VOLUME.into(),
+            host_path: Some(HostPathVolumeSource {
+                path: self.socket_dir().to_string_lossy().into_owned(),
+                type_: Some("Directory".into()),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn socket_mount(&self) -> VolumeMount {
+        VolumeMount {
+            name: AGENT_SOCKET_VOLUME.into(),
+            mount_path: self.socket_dir().to_string_lossy().into_owned(),
+            read_only: Some(true),
+            ..Default::default()
+        }
+    }
+
+    // kubelet rotates the token before it expires
+    fn token_volume(&self) -> Volume {
+        Volume {
+            name: TOKEN_VOLUME.into(),
+            projected: Some(ProjectedVolumeSource {
+                sources: Some(vec![VolumeProjection {
+                    service_account_token: Some(ServiceAccountTokenProjection {
+                        path: "token".into(),
+                        expiration_seconds: Some(PROJECTED_TOKEN_EXPIRATION),
+                        audience: None,
+                    }),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    // mounts of the volumes pods reach the agent through
+    fn agent_mounts(&self) -> Vec<VolumeMount> {
+        let mut mounts = vec![];
+        if self.endpoint == AgentEndpointMode::Socket {
+            mounts.push(self.socket_mount());
+        }
+        if self.uses_tls() {
+            mounts.push(self.ca_mount());
+        }
+        mounts
+    }
+
+    fn token_mount(&self) -> VolumeMount {
+        VolumeMount {
+            name: TOKEN_VOLUME.into(),
+            mount_path: PROJECTED_TOKEN_DIR.into(),
+            read_only: Some(true),
+            ..Default::default()
+        }
+    }
+
+    // native sidecar so the proxy is running before and after the app containers
+    fn proxy_container(&self) -> Container {
+        Container {
+            name: PROXY_CONTAINER.into(),
+            image: self.proxy_image.clone(),
+            args: Some(vec![
+                "proxy".into(),
+                format!("--socket-path={}", self.agent_socket_path.to_string_lossy()),
+                format!("--listen-address=127.0.0.1:{}", self.proxy_port),
+            ]),
+            restart_policy: Some("Always".into()),
+            volume_mounts: Some(vec![self.socket_mount()]),
+            security_context: Some(SecurityContext {
+                allow_privilege_escalation: Some(false),
+                read_only_root_filesystem: Some(true),
+                run_as_non_root: Some(true),
+                run_as_user: Some(65532),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+}
 
-pub fn create_pod_patch(pod: &Pod, agent_address: &str, region: &str) -> Patch {
+#[tracing::instrument(name = "create_pod_patch", skip_all, fields(pod = pod.metadata.name.as_deref().or(pod.metadata.generate_name.as_deref())))]
+pub(crate) fn create_pod_patch(
+    pod: &Pod,
+    cfg: &PatchConfig,
+    aliases: &[&str],
+    annotations: &PodAnnotations,
+) -> Patch {
     let Some(ref spec) = pod.spec else {
         return Patch(vec![]);
     };
+    let adds_proxy =
+        cfg.endpoint == AgentEndpointMode::Proxy && !has_init_container(spec, PROXY_CONTAINER);
+    // the sidecar is inserted before the other init containers, which moves them down
+    let init_offset = usize::from(adds_proxy);
+    let targets: Vec<Target> = spec
+        .init_containers
+        .iter()
+        .flatten()
+        .enumerate()
+        .filter(|(_, container)| container.name != PROXY_CONTAINER)
+        .map(|(idx, container)| Target::container("initContainers", idx + init_offset, container))
+        .chain(
+            spec.containers
+                .iter()
+                .enumerate()
+                .map(|(idx, container)| Target::container("containers", idx, container)),
+        )
+        .filter(|target| annotations.injects(target.name))
+        .collect();
+    // the volumes and the sidecar are only used by the injected containers
+    if targets.is_empty() {
+        return Patch(vec![]);
+    }
+
     let mut patches = vec![];
-    for (idx, container) in spec.containers.iter().enumerate() {
-        let idxstr = idx.to_string();
-        let mut tokens = vec!["spec", "containers", idxstr.as_str(), "env"];
-        if let Some(ref env) = container.env {
-            tokens.push("-");
-            let path = PointerBuf::from_tokens(tokens);
-            if !contains_aws_cred_env(env) {
-                patches.push(json_patch::PatchOperation::Add(json_patch::AddOperation {
-                    path: path.clone(),
-                    value: serde_json::to_value(EnvVar {
-                        name: ENV_AWS_FULL_URI.to_string(),
-                        value: Some(format!("http://{agent_address}/v1/container-credentials")),
-                        ..Default::default()
-                    })
-                    .unwrap(),
-                }));
-                patches.push(json_patch::PatchOperation::Add(json_patch::AddOperation {
-                    path: path.clone(),
-                    value: serde_json::to_value(EnvVar {
-                        name: ENV_AWS_TOKEN_FILE.to_string(),
-                        value: Some(TOKEN_PATH.into()),
-                        ..Default::default()
-                    })
-                    .unwrap(),
-                }));
-            }
-            if !contains_aws_region_env(env) {
-                patches.push(json_patch::PatchOperation::Add(json_patch::AddOperation {
-                    path: path.clone(),
-                    value: serde_json::to_value(EnvVar {
-                        name: ENV_AWS_DEFAULT_REGION.to_string(),
-                        value: Some(region.into()),
-                        ..Default::default()
-                    })
-                    .unwrap(),
-                }));
-                patches.push(json_patch::PatchOperation::Add(json_patch::AddOperation {
-                    path: path.clone(),
-                    value: serde_json::to_value(EnvVar {
-                        name: ENV_AWS_REGION.to_string(),
-                        value: Some(region.into()),
-                        ..Default::default()
-                    })
-                    .unwrap(),
-                }));
-            }
+    let mut volumes = spec.volumes.is_some();
+    let mut add_volume = |patches: &mut Vec<PatchOperation>, volume: Volume| {
+        patches.push(append(&["spec", "volumes"], volumes, volume));
+        volumes = true;
+    };
+    let uses_socket = cfg.endpoint != AgentEndpointMode::Tcp;
+    if uses_socket && !has_volume(spec, AGENT_SOCKET_VOLUME) {
+        add_volume(&mut patches, cfg.socket_volume());
+    }
+    if cfg.uses_tls() && !has_volume(spec, AGENT_CA_VOLUME) {
+        add_volume(&mut patches, cfg.ca_volume());
+    }
+    // the service account admission plugin has mounted the API token into every
+    // container unless automounting is disabled for the pod or its service account
+    let needs_token = targets.iter().any(|target| !target.mounts_api_token());
+    if needs_token && !has_volume(spec, TOKEN_VOLUME) {
+        add_volume(&mut patches, cfg.token_volume());
+    }
+    if adds_proxy {
+        // sidecars go first so credentials are available to other init containers
+        patches.push(match spec.init_containers {
+            Some(_) => add(&["spec", "initContainers", "0"], cfg.proxy_container()),
+            None => add(&["spec", "initContainers"], vec![cfg.proxy_container()]),
+        });
+    }
+    for target in targets {
+        let mut mounts = cfg.agent_mounts();
+        let token_path = if target.mounts_api_token() {
+            TOKEN_PATH
         } else {
-            let path = PointerBuf::from_tokens(tokens);
-            patches.push(json_patch::PatchOperation::Add(json_patch::AddOperation {
-                path: path.clone(),
-                value: serde_json::to_value(vec![
-                    EnvVar {
-                        name: ENV_AWS_FULL_URI.to_string(),
-                        value: Some(format!("http://{agent_address}/v1/container-credentials")),
-                        ..Default::default()
-                    },
-                    EnvVar {
-                        name: ENV_AWS_TOKEN_FILE.to_string(),
-                        value: Some(TOKEN_PATH.into()),
-                        ..Default::default()
-                    },
-                    EnvVar {
-                        name: ENV_AWS_DEFAULT_REGION.to_string(),
-                        value: Some(region.to_string()),
-                        ..Default::default()
-                    },
-                    EnvVar {
-                        name: ENV_AWS_REGION.to_string(),
-                        value: Some(region.to_string()),
-                        ..Default::default()
-                    },
-                ])
-                .unwrap(),
-            }));
+            mounts.push(cfg.token_mount());
+            PROJECTED_TOKEN_PATH
+        };
+        patch_container(
+            &mut patches,
+            cfg,
+            aliases,
+            annotations,
+            &target,
+            token_path,
+            mounts,
+        );
+    }
+    Patch(patches)
+}
+
+/// Patches the ephemeral containers added to a pod, such as debug containers. Only
+/// containers that were not in the pod before are patched as the others cannot change,
+/// and only in pods that were mutated when they were created as the volumes they mount
+/// cannot be added anymore.
+#[tracing::instrument(name = "create_ephemeral_patch", skip_all, fields(pod = pod.metadata.name.as_deref()))]
+pub(crate) fn create_ephemeral_patch(
+    pod: &Pod,
+    old_pod: Option<&Pod>,
+    cfg: &PatchConfig,
+    aliases: &[&str],
+    annotations: &PodAnnotations,
+) -> Patch {
+    let Some(ref spec) = pod.spec else {
+        return Patch(vec![]);
+    };
+    if !was_mutated(spec, cfg) {
+        return Patch(vec![]);
+    }
+    let existing: HashSet<&str> = old_pod
+        .and_then(|pod| pod.spec.as_ref())
+        .and_then(|spec| spec.ephemeral_containers.as_ref())
+        .into_iter()
+        .flatten()
+        .map(|container| container.name.as_str())
+        .collect();
+    let mut patches = vec![];
+    for (idx, container) in spec.ephemeral_containers.iter().flatten().enumerate() {
+        let target = Target::ephemeral(idx, container);
+        if existing.contains(target.name) || !annotations.injects(target.name) {
+            continue;
+        }
+        let mut mounts = cfg.agent_mounts();
+        // ephemeral containers do not get the API token mounted, the volume of the
+        // other containers is mounted instead
+        let token_path = if target.mounts_api_token() {
+            TOKEN_PATH
+        } else if has_volume(spec, TOKEN_VOLUME) {
+            mounts.push(cfg.token_mount());
+            PROJECTED_TOKEN_PATH
+        } else if let Some(volume) = api_token_volume(spec) {
+            mounts.push(VolumeMount {
+                name: volume.into(),
+                mount_path: SERVICE_ACCOUNT_DIR.into(),
+                read_only: Some(true),
+                ..Default::default()
+            });
+            TOKEN_PATH
+        } else {
+            // without a token every credential request of the container would fail
+            continue;
         };
+        patch_container(
+            &mut patches,
+            cfg,
+            aliases,
+            annotations,
+            &target,
+            token_path,
+            mounts,
+        );
     }
     Patch(patches)
 }
 
+// A container in one of the pod's lists, ephemeral containers have a type of their own
+struct Target<'a> {
+    list: &'static str,
+    idx: String,
+    name: &'a str,
+    env: Option<&'a [EnvVar]>,
+    volume_mounts: &'a [VolumeMount],
+    has_mounts: bool,
+}
+
+impl<'a> Target<'a> {
+    fn container(list: &'static str, idx: usize, container: &'a Container) -> Self {
+        Self {
+            list,
+            idx: idx.to_string(),
+            name: &container.name,
+            env: container.env.as_deref(),
+            volume_mounts: container.volume_mounts.as_deref().unwrap_or_default(),
+            has_mounts: container.volume_mounts.is_some(),
+        }
+    }
+
+    fn ephemeral(idx: usize, container: &'a EphemeralContainer) -> Self {
+        Self {
+            list: "ephemeralContainers",
+            idx: idx.to_string(),
+            name: &container.name,
+            env: container.env.as_deref(),
+            volume_mounts: container.volume_mounts.as_deref().unwrap_or_default(),
+            has_mounts: container.volume_mounts.is_some(),
+        }
+    }
+
+    fn path<'p>(&'p self, field: &'p str) -> [&'p str; 4] {
+        ["spec", self.list, self.idx.as_str(), field]
+    }
+
+    fn mounts_volume(&self, name: &str) -> bool {
+        self.volume_mounts.iter().any(|mount| mount.name == name)
+    }
+
+    // whether the container has the service account token Kubernetes mounts for API
+    // access
+    fn mounts_api_token(&self) -> bool {
+        self.volume_mounts.iter().any(|mount| {
+            let path = Path::new(&mount.mount_path);
+            path == Path::new(SERVICE_ACCOUNT_DIR) || path == Path::new(TOKEN_PATH)
+        })
+    }
+}
+
+// adds the credential env and the volume mounts the container does not have yet
+fn patch_container(
+    patches: &mut Vec<PatchOperation>,
+    cfg: &PatchConfig,
+    aliases: &[&str],
+    annotations: &PodAnnotations,
+    target: &Target,
+    token_path: &str,
+    mounts: Vec<VolumeMount>,
+) {
+    if let Some(env) = target.env {
+        let mut env_vars = vec![];
+        if !contains_aws_cred_env(env) {
+            env_vars.extend(cfg.credential_env(aliases, token_path));
+        }
+        if !contains_aws_region_env(env) {
+            env_vars.extend(cfg.region_env(annotations));
+        }
+        if cfg.uses_tls() {
+            env_vars.extend(cfg.ca_env(env));
+        }
+        let path = target.path("env");
+        patches.extend(
+            env_vars
+                .into_iter()
+                .map(|env_var| append(&path, true, env_var)),
+        );
+    } else {
+        let mut env_vars = cfg.credential_env(aliases, token_path);
+        env_vars.extend(cfg.region_env(annotations));
+        if cfg.uses_tls() {
+            env_vars.extend(cfg.ca_env(&[]));
+        }
+        patches.push(add(&target.path("env"), env_vars));
+    };
+    let mut exists = target.has_mounts;
+    for mount in mounts {
+        if !target.mounts_volume(&mount.name) {
+            patches.push(append(&target.path("volumeMounts"), exists, mount));
+            exists = true;
+        }
+    }
+}
+
+// `AWS_CONTAINER_CREDENTIALS_FULL_URI_<ALIAS>` with `-` replaced as it is not valid
+// in shell variable names. SDKs do not read these, they are for code that selects a
+// role by creating its credentials provider with the URI
+fn alias_env_name(alias: &str) -> String {
+    format!(
+        "{ENV_AWS_FULL_URI}_{}",
+        alias.to_ascii_uppercase().replace('-', "_")
+    )
+}
+
+fn env_var(name: &str, value: impl Into<String>) -> EnvVar {
+    EnvVar {
+        name: name.into(),
+        value: Some(value.into()),
+        ..Default::default()
+    }
+}
+
+fn add(tokens: &[&str], value: impl Serialize) -> PatchOperation {
+    PatchOperation::Add(AddOperation {
+        path: PointerBuf::from_tokens(tokens.iter().copied()),
+        value: serde_json::to_value(value).unwrap(),
+    })
+}
+
+// appends to the list at `tokens`, creating it when it does not exist yet
+fn append<T: Serialize>(tokens: &[&str], exists: bool, value: T) -> PatchOperation {
+    if exists {
+        let mut tokens = tokens.to_vec();
+        tokens.push("-");
+        add(&tokens, value)
+    } else {
+        add(tokens, vec![value])
+    }
+}
+
+fn has_volume(spec: &PodSpec, name: &str) -> bool {
+    spec.volumes
+        .iter()
+        .flatten()
+        .any(|volume| volume.name == name)
+}
+
+fn has_init_container(spec: &PodSpec, name: &str) -> bool {
+    spec.init_containers
+        .iter()
+        .flatten()
+        .any(|container| container.name == name)
+}
+
+// volume of the API token the service account admission plugin mounted
+fn api_token_volume(spec: &PodSpec) -> Option<&str> {
+    spec.containers
+        .iter()
+        .flat_map(|container| container.volume_mounts.iter().flatten())
+        .find(|mount| Path::new(&mount.mount_path) == Path::new(SERVICE_ACCOUNT_DIR))
+        .map(|mount| mount.name.as_str())
+}
+
+// whether the pod has what containers need to reach the agent, which is added when
+// it is created
+fn was_mutated(spec: &PodSpec, cfg: &PatchConfig) -> bool {
+    match cfg.endpoint {
+        AgentEndpointMode::Tcp => !cfg.uses_tls() || has_volume(spec, AGENT_CA_VOLUME),
+        AgentEndpointMode::Socket => has_volume(spec, AGENT_SOCKET_VOLUME),
+        AgentEndpointMode::Proxy => has_init_container(spec, PROXY_CONTAINER),
+    }
+}
+
 // checks if the environment variables contain aws credential env
 fn contains_aws_cred_env(env: &[EnvVar]) -> bool {
     env.iter().any(|nv| {
@@ -99,6 +555,7 @@ fn contains_aws_cred_env(env: &[EnvVar]) -> bool {
             || nv.name == "AWS_CONTAINER_CREDENTIALS_RELATIVE_URI"
             || nv.name == "AWS_CONTAINER_AUTHORIZATION_TOKEN"
             || nv.name == "AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE"
+            || nv.name == ENV_AGENT_SOCKET
     })
 }
 
@@ -110,20 +567,52 @@ fn contains_aws_region_env(env: &[EnvVar]) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use k8s_openapi::api::core::v1::{Container, PodSpec};
     use serde_json::from_value;
     use serde_json::json;
 
     use super::*;
 
+    fn patch_config(endpoint: AgentEndpointMode) -> PatchConfig {
+        PatchConfig {
+            agent_address: "169.254.170.23:8080".into(),
+            region: "us-west-2".into(),
+            endpoint,
+            agent_socket_path: "/var/run/homelab-aws-creds/agent.sock".into(),
+            proxy_image: Some("homelab-aws-creds:latest".into()),
+            proxy_port: 8181,
+            agent_tls: false,
+            agent_ca_path: "/var/run/homelab-aws-creds/ca.crt".into(),
+            aws_ca_bundle: false,
+        }
+    }
+
+    const NO_ANNOTATIONS: PodAnnotations = PodAnnotations {
+        skip: false,
+        inject_containers: None,
+        skip_containers: vec![],
+        region: None,
+    };
+
+    // mounted by the service account admission plugin unless automounting is disabled
+    fn api_token_mount() -> VolumeMount {
+        VolumeMount {
+            name: "kube-api-access".into(),
+            mount_path: SERVICE_ACCOUNT_DIR.into(),
+            read_only: Some(true),
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn test_create_pod_patch() {
-        let agent_address = "169.254.170.23:8080";
-        let region = "us-west-2";
+        let cfg = patch_config(AgentEndpointMode::Tcp);
+        let agent_address = cfg.agent_address.as_str();
+        let region = cfg.region.as_str();
         let pod = Pod {
             spec: Some(PodSpec {
                 containers: vec![Container {
                     name: "test".into(),
+                    volume_mounts: Some(vec![api_token_mount()]),
                     env: None,
                     ..Default::default()
                 }],
@@ -132,7 +621,7 @@ mod tests {
             ..Default::default()
         };
         assert_eq!(
-            create_pod_patch(&pod, agent_address, region),
+            create_pod_patch(&pod, &cfg, &[], &NO_ANNOTATIONS),
             from_value::<Patch>(json!([
               {
                 "op": "add",
@@ -163,6 +652,7 @@ mod tests {
             spec: Some(PodSpec {
                 containers: vec![Container {
                     name: "test".into(),
+                    volume_mounts: Some(vec![api_token_mount()]),
                     env: Some(vec![EnvVar {
                         name: "test".into(),
                         value: Some("test".into()),
@@ -175,7 +665,7 @@ mod tests {
             ..Default::default()
         };
         assert_eq!(
-            create_pod_patch(&pod, agent_address, region),
+            create_pod_patch(&pod, &cfg, &[], &NO_ANNOTATIONS),
             from_value::<Patch>(json!([
               {
                 "op": "add",
@@ -222,6 +712,7 @@ mod tests {
                 containers: vec![
                     Container {
                         name: "test".into(),
+                        volume_mounts: Some(vec![api_token_mount()]),
                         env: Some(vec![EnvVar {
                             name: "test".into(),
                             value: Some("test".into()),
@@ -231,6 +722,7 @@ mod tests {
                     },
                     Container {
                         name: "test2".into(),
+                        volume_mounts: Some(vec![api_token_mount()]),
                         env: Some(vec![EnvVar {
                             name: "test".into(),
                             value: Some("test".into()),
@@ -244,7 +736,7 @@ mod tests {
             ..Default::default()
         };
         assert_eq!(
-            create_pod_patch(&pod, agent_address, region),
+            create_pod_patch(&pod, &cfg, &[], &NO_ANNOTATIONS),
             from_value::<Patch>(json!([
               {
                 "op": "add",
@@ -322,4 +814,446 @@ mod tests {
             .unwrap()
         );
     }
+
+    #[test]
+    fn socket_endpoint_patch() {
+        let cfg = patch_config(AgentEndpointMode::Socket);
+        let pod = Pod {
+            spec: Some(PodSpec {
+                containers: vec![Container {
+                    name: "test".into(),
+                    volume_mounts: Some(vec![api_token_mount()]),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            create_pod_patch(&pod, &cfg, &[], &NO_ANNOTATIONS),
+            from_value::<Patch>(json!([
+              {
+                "op": "add",
+                "path": "/spec/volumes",
+                "value": [{
+                    "name": "homelab-aws-creds-agent",
+                    "hostPath": {"path": "/var/run/homelab-aws-creds", "type": "Directory"}
+                }]
+              },
+              {
+                "op": "add",
+                "path": "/spec/containers/0/env",
+                "value": [
+                    {"name": "HOMELAB_AWS_CREDS_SOCKET", "value": "/var/run/homelab-aws-creds/agent.sock"},
+                    {"name": "AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE", "value": "/var/run/secrets/kubernetes.io/serviceaccount/token"},
+                    {"name": "AWS_DEFAULT_REGION", "value": "us-west-2"},
+                    {"name": "AWS_REGION", "value": "us-west-2"}
+                ]
+              },
+              {
+                "op": "add",
+                "path": "/spec/containers/0/volumeMounts/-",
+                "value": {
+                    "name": "homelab-aws-creds-agent",
+                    "mountPath": "/var/run/homelab-aws-creds",
+                    "readOnly": true
+                }
+              }
+            ]))
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn tls_endpoint_patch() {
+        let cfg = PatchConfig {
+            agent_tls: true,
+            ..patch_config(AgentEndpointMode::Tcp)
+        };
+        let pod = Pod {
+            spec: Some(PodSpec {
+                containers: vec![Container {
+                    name: "test".into(),
+                    volume_mounts: Some(vec![api_token_mount()]),
+                    env: Some(vec![env_var(ENV_AWS_CA_BUNDLE, "/etc/ssl/custom.pem")]),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            create_pod_patch(&pod, &cfg, &[], &NO_ANNOTATIONS),
+            from_value::<Patch>(json!([
+              {
+                "op": "add",
+                "path": "/spec/volumes",
+                "value": [{
+                    "name": "homelab-aws-creds-ca",
+                    "hostPath": {"path": "/var/run/homelab-aws-creds", "type": "Directory"}
+                }]
+              },
+              {
+                "op": "add",
+                "path": "/spec/containers/0/env/-",
+                "value": {"name": "AWS_CONTAINER_CREDENTIALS_FULL_URI", "value": "https://169.254.170.23:8080/v1/container-credentials"}
+              },
+              {
+                "op": "add",
+                "path": "/spec/containers/0/env/-",
+                "value": {"name": "AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE", "value": "/var/run/secrets/kubernetes.io/serviceaccount/token"}
+              },
+              {
+                "op": "add",
+                "path": "/spec/containers/0/env/-",
+                "value": {"name": "AWS_DEFAULT_REGION", "value": "us-west-2"}
+              },
+              {
+                "op": "add",
+                "path": "/spec/containers/0/env/-",
+                "value": {"name": "AWS_REGION", "value": "us-west-2"}
+              },
+              {
+                "op": "add",
+                "path": "/spec/containers/0/env/-",
+                "value": {"name": "NODE_EXTRA_CA_CERTS", "value": "/var/run/homelab-aws-creds/ca.crt"}
+              },
+              {
+                "op": "add",
+                "path": "/spec/containers/0/volumeMounts/-",
+                "value": {
+                    "name": "homelab-aws-creds-ca",
+                    "mountPath": "/var/run/homelab-aws-creds",
+                    "readOnly": true
+                }
+              }
+            ]))
+            .unwrap()
+        );
+
+        // AWS_CA_BUNDLE replaces the SDK's roots so it is only set when enabled
+        let names = |cfg: &PatchConfig| {
+            let env = cfg.ca_env(&[]);
+            env.into_iter().map(|nv| nv.name).collect::<Vec<_>>()
+        };
+        assert_eq!(names(&cfg), [ENV_NODE_EXTRA_CA_CERTS]);
+        let cfg = PatchConfig {
+            aws_ca_bundle: true,
+            ..cfg
+        };
+        assert_eq!(names(&cfg), [ENV_NODE_EXTRA_CA_CERTS, ENV_AWS_CA_BUNDLE]);
+    }
+
+    #[test]
+    fn projected_token_patch() {
+        let cfg = patch_config(AgentEndpointMode::Socket);
+        let pod = Pod {
+            spec: Some(PodSpec {
+                automount_service_account_token: Some(false),
+                containers: vec![Container {
+                    name: "test".into(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let patch = create_pod_patch(&pod, &cfg, &[], &NO_ANNOTATIONS);
+        assert_eq!(
+            patch,
+            from_value::<Patch>(json!([
+              {
+                "op": "add",
+                "path": "/spec/volumes",
+                "value": [{
+                    "name": "homelab-aws-creds-agent",
+                    "hostPath": {"path": "/var/run/homelab-aws-creds", "type": "Directory"}
+                }]
+              },
+              {
+                "op": "add",
+                "path": "/spec/volumes/-",
+                "value": {
+                    "name": "homelab-aws-creds-token",
+                    "projected": {"sources": [{
+                        "serviceAccountToken": {"path": "token", "expirationSeconds": 3600}
+                    }]}
+                }
+              },
+              {
+                "op": "add",
+                "path": "/spec/containers/0/env",
+                "value": [
+                    {"name": "HOMELAB_AWS_CREDS_SOCKET", "value": "/var/run/homelab-aws-creds/agent.sock"},
+                    {"name": "AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE", "value": "/var/run/secrets/homelab-aws-creds/token"},
+                    {"name": "AWS_DEFAULT_REGION", "value": "us-west-2"},
+                    {"name": "AWS_REGION", "value": "us-west-2"}
+                ]
+              },
+              {
+                "op": "add",
+                "path": "/spec/containers/0/volumeMounts",
+                "value": [{
+                    "name": "homelab-aws-creds-agent",
+                    "mountPath": "/var/run/homelab-aws-creds",
+                    "readOnly": true
+                }]
+              },
+              {
+                "op": "add",
+                "path": "/spec/containers/0/volumeMounts/-",
+                "value": {
+                    "name": "homelab-aws-creds-token",
+                    "mountPath": "/var/run/secrets/homelab-aws-creds",
+                    "readOnly": true
+                }
+              }
+            ]))
+            .unwrap()
+        );
+
+        // reinvocation does not add the token again
+        let mut patched = serde_json::to_value(&pod).unwrap();
+        json_patch::patch(&mut patched, &patch).unwrap();
+        let patched: Pod = from_value(patched).unwrap();
+        assert!(create_pod_patch(&patched, &cfg, &[], &NO_ANNOTATIONS).is_empty());
+    }
+
+    #[test]
+    fn proxy_endpoint_patch() {
+        let cfg = patch_config(AgentEndpointMode::Proxy);
+        let pod = Pod {
+            spec: Some(PodSpec {
+                containers: vec![Container {
+                    name: "test".into(),
+                    volume_mounts: Some(vec![api_token_mount()]),
+                    env: Some(vec![env_var(ENV_AWS_REGION, "eu-west-1")]),
+                    ..Default::default()
+                }],
+                init_containers: Some(vec![Container {
+                    name: "init".into(),
+                    volume_mounts: Some(vec![api_token_mount()]),
+                    ..Default::default()
+                }]),
+                volumes: Some(vec![Volume {
+                    name: "data".into(),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let patch = create_pod_patch(&pod, &cfg, &[], &NO_ANNOTATIONS);
+        let paths: Vec<String> = patch
+            .iter()
+            .map(|op| match op {
+                PatchOperation::Add(add) => add.path.to_string(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "/spec/volumes/-",
+                "/spec/initContainers/0",
+                "/spec/initContainers/1/env",
+                "/spec/containers/0/env/-",
+                "/spec/containers/0/env/-",
+            ]
+        );
+        let PatchOperation::Add(ref env) = patch[3] else {
+            unreachable!()
+        };
+        assert_eq!(
+            env.value,
+            json!({"name": "AWS_CONTAINER_CREDENTIALS_FULL_URI", "value": "http://127.0.0.1:8181/v1/container-credentials"})
+        );
+
+        // the init container moved down and reaches the agent through the sidecar too
+        let PatchOperation::Add(ref env) = patch[2] else {
+            unreachable!()
+        };
+        assert_eq!(
+            env.value[0],
+            json!({"name": "AWS_CONTAINER_CREDENTIALS_FULL_URI", "value": "http://127.0.0.1:8181/v1/container-credentials"})
+        );
+
+        // reinvocation does not add the sidecar or volume again
+        let mut patched = serde_json::to_value(&pod).unwrap();
+        json_patch::patch(&mut patched, &patch).unwrap();
+        let patched: Pod = from_value(patched).unwrap();
+        assert!(create_pod_patch(&patched, &cfg, &[], &NO_ANNOTATIONS).is_empty());
+    }
+
+    #[test]
+    fn annotated_pod_patch() {
+        let cfg = patch_config(AgentEndpointMode::Tcp);
+        let container = |name: &str| Container {
+            name: name.into(),
+            volume_mounts: Some(vec![api_token_mount()]),
+            ..Default::default()
+        };
+        let pod = Pod {
+            spec: Some(PodSpec {
+                init_containers: Some(vec![container("migrate"), container("migrate-logs")]),
+                containers: vec![container("app"), container("log-shipper")],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let annotations = PodAnnotations {
+            skip_containers: vec!["log-shipper".into(), "migrate-logs".into()],
+            region: Some("eu-west-1".into()),
+            ..Default::default()
+        };
+        let patch = create_pod_patch(&pod, &cfg, &[], &annotations);
+        let paths: Vec<String> = patch
+            .iter()
+            .map(|op| match op {
+                PatchOperation::Add(add) => add.path.to_string(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            ["/spec/initContainers/0/env", "/spec/containers/0/env"]
+        );
+        let PatchOperation::Add(ref env) = patch[1] else {
+            unreachable!()
+        };
+        assert_eq!(
+            env.value[3],
+            json!({"name": "AWS_REGION", "value": "eu-west-1"})
+        );
+    }
+
+    #[test]
+    fn skipped_pod_patch() {
+        let cfg = patch_config(AgentEndpointMode::Proxy);
+        let container = |name: &str| Container {
+            name: name.into(),
+            ..Default::default()
+        };
+        let pod = Pod {
+            spec: Some(PodSpec {
+                init_containers: Some(vec![container("migrate")]),
+                containers: vec![container("app"), container("log-shipper")],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let annotations = PodAnnotations {
+            inject_containers: Some(vec!["app".into()]),
+            skip_containers: vec!["app".into()],
+            ..Default::default()
+        };
+        let patch = create_pod_patch(&pod, &cfg, &[], &annotations);
+        assert!(patch.is_empty(), "{patch:?}");
+    }
+
+    #[test]
+    fn ephemeral_container_patch() {
+        let cfg = patch_config(AgentEndpointMode::Socket);
+        let debug = |name: &str| EphemeralContainer {
+            name: name.into(),
+            ..Default::default()
+        };
+        let old_pod = Pod {
+            spec: Some(PodSpec {
+                containers: vec![Container {
+                    name: "test".into(),
+                    volume_mounts: Some(vec![api_token_mount()]),
+                    ..Default::default()
+                }],
+                ephemeral_containers: Some(vec![debug("debugger-1")]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut pod = old_pod.clone();
+        let spec = pod.spec.as_mut().unwrap();
+        spec.ephemeral_containers
+            .as_mut()
+            .unwrap()
+            .push(debug("debugger-2"));
+
+        // pods that were not mutated when they were created lack the volumes
+        assert!(
+            create_ephemeral_patch(&pod, Some(&old_pod), &cfg, &[], &NO_ANNOTATIONS).is_empty()
+        );
+
+        pod.spec.as_mut().unwrap().volumes = Some(vec![cfg.socket_volume()]);
+        // without a token to mount the container is left alone
+        let mut tokenless = pod.clone();
+        tokenless.spec.as_mut().unwrap().containers[0].volume_mounts = None;
+        assert!(
+            create_ephemeral_patch(&tokenless, Some(&old_pod), &cfg, &[], &NO_ANNOTATIONS)
+                .is_empty()
+        );
+        assert_eq!(
+            create_ephemeral_patch(&pod, Some(&old_pod), &cfg, &[], &NO_ANNOTATIONS),
+            from_value::<Patch>(json!([
+              {
+                "op": "add",
+                "path": "/spec/ephemeralContainers/1/env",
+                "value": [
+                    {"name": "HOMELAB_AWS_CREDS_SOCKET", "value": "/var/run/homelab-aws-creds/agent.sock"},
+                    {"name": "AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE", "value": "/var/run/secrets/kubernetes.io/serviceaccount/token"},
+                    {"name": "AWS_DEFAULT_REGION", "value": "us-west-2"},
+                    {"name": "AWS_REGION", "value": "us-west-2"}
+                ]
+              },
+              {
+                "op": "add",
+                "path": "/spec/ephemeralContainers/1/volumeMounts",
+                "value": [
+                    {"name": "homelab-aws-creds-agent", "mountPath": "/var/run/homelab-aws-creds", "readOnly": true}
+                ]
+              },
+              {
+                "op": "add",
+                "path": "/spec/ephemeralContainers/1/volumeMounts/-",
+                "value": {"name": "kube-api-access", "mountPath": "/var/run/secrets/kubernetes.io/serviceaccount", "readOnly": true}
+              }
+            ]))
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn role_alias_patch() {
+        let pod = Pod {
+            spec: Some(PodSpec {
+                containers: vec![Container {
+                    name: "test".into(),
+                    volume_mounts: Some(vec![api_token_mount()]),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let env = |cfg: &PatchConfig| {
+            let patch = create_pod_patch(&pod, cfg, &["kms", "data-lake"], &NO_ANNOTATIONS);
+            let Some(PatchOperation::Add(add)) = patch.iter().find(
+                |op| matches!(op, PatchOperation::Add(add) if add.path == "/spec/containers/0/env"),
+            ) else {
+                unreachable!()
+            };
+            add.value.as_array().unwrap().clone()
+        };
+        assert_eq!(
+            env(&patch_config(AgentEndpointMode::Tcp))[2..4],
+            [
+                json!({"name": "AWS_CONTAINER_CREDENTIALS_FULL_URI_KMS", "value": "http://169.254.170.23:8080/v1/container-credentials/kms"}),
+                json!({"name": "AWS_CONTAINER_CREDENTIALS_FULL_URI_DATA_LAKE", "value": "http://169.254.170.23:8080/v1/container-credentials/data-lake"}),
+            ]
+        );
+        assert_eq!(
+            env(&patch_config(AgentEndpointMode::Proxy))[2],
+            json!({"name": "AWS_CONTAINER_CREDENTIALS_FULL_URI_KMS", "value": "http://127.0.0.1:8181/v1/container-credentials/kms"})
+        );
+        // clients select the role by path on the socket
+        assert_eq!(env(&patch_config(AgentEndpointMode::Socket)).len(), 4);
+    }
 }
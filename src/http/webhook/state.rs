@@ -1,6 +1,10 @@
-use crate::http::mappings::Mapping;
+use std::sync::Arc;
+
+use crate::config::ServerConfig;
+use crate::http::mappings::{Mapping, ServiceRoleMapping};
 use crate::http::middleware::add_default_middleware;
-use crate::http::webhook::patch::create_pod_patch;
+use crate::http::webhook::annotations::PodAnnotations;
+use crate::http::webhook::patch::{create_ephemeral_patch, create_pod_patch, PatchConfig};
 use axum::extract::State;
 use axum::routing::post;
 use axum::{Json, Router};
@@ -9,39 +13,42 @@ use k8s_openapi::api::core::v1::Pod;
 use kube::api::DynamicObject;
 use kube::core::admission::{AdmissionRequest, AdmissionResponse, AdmissionReview};
 use kube::ResourceExt;
-use tracing::{error, trace};
+use tracing::{error, trace, warn};
+
+/// Subresource debug containers are added to running pods through
+const EPHEMERAL_CONTAINERS: &str = "ephemeralcontainers";
 
 #[derive(Clone)]
 pub(crate) struct WebhookState {
     role_mappings: Mapping,
-    agent_address: String,
-    aws_region: String,
+    patch_config: PatchConfig,
 }
 
 impl WebhookState {
-    pub(crate) fn new(role_mappings: Mapping, agent_address: String, aws_region: String) -> Self {
+    pub(crate) fn new(role_mappings: Mapping, patch_config: PatchConfig) -> Self {
         Self {
             role_mappings,
-            agent_address,
-            aws_region,
+            patch_config,
         }
     }
-    fn should_mutate(&self, service_account: Option<String>, namespace: Option<String>) -> bool {
-        if let (Some(sa), Some(ns)) = (service_account, namespace) {
-            self.role_mappings
-                .get_role(ns.as_str(), sa.as_str())
-                .is_some()
-        } else {
-            false
-        }
+    /// Mapping of the pod's service account, pods without one are not mutated
+    fn pod_mapping(
+        &self,
+        service_account: Option<String>,
+        namespace: Option<String>,
+    ) -> Option<Arc<ServiceRoleMapping>> {
+        let (Some(sa), Some(ns)) = (service_account, namespace) else {
+            return None;
+        };
+        self.role_mappings.get_role(ns.as_str(), sa.as_str())
     }
 }
 
-pub(crate) fn new_webhook_router(webhook_state: WebhookState) -> Router {
+pub(crate) fn new_webhook_router(webhook_state: WebhookState, cfg: &ServerConfig) -> Router {
     let rt = Router::new()
         .route("/v1/mutate/pods", post(mutate_pod_handler))
         .with_state(webhook_state);
-    add_default_middleware(rt)
+    add_default_middleware(rt, cfg)
 }
 
 async fn mutate_pod_handler(
@@ -59,7 +66,7 @@ async fn mutate_pod_handler(
     let og_res = res.clone();
     let mut patch = Patch(vec![]);
     if let Some(ref pod) = req.object {
-        if state.should_mutate(
+        if let Some(mapping) = state.pod_mapping(
             pod.spec
                 .to_owned()
                 .unwrap_or_default()
@@ -67,7 +74,28 @@ async fn mutate_pod_handler(
                 .to_owned(),
             pod.namespace(),
         ) {
-            patch = create_pod_patch(pod, &state.agent_address, &state.aws_region);
+            let (annotations, warnings) = PodAnnotations::parse(pod);
+            if !warnings.is_empty() {
+                warn!(
+                    "pod {} has invalid annotations: {}",
+                    pod.name_any(),
+                    warnings.join("; ")
+                );
+                res.warnings = Some(warnings);
+            }
+            let aliases = mapping.aliases();
+            if !annotations.skip {
+                patch = match req.sub_resource.as_deref() {
+                    Some(EPHEMERAL_CONTAINERS) => create_ephemeral_patch(
+                        pod,
+                        req.old_object.as_ref(),
+                        &state.patch_config,
+                        &aliases,
+                        &annotations,
+                    ),
+                    _ => create_pod_patch(pod, &state.patch_config, &aliases, &annotations),
+                };
+            }
         }
         trace!("{}", &patch);
         res = match res.with_patch(patch) {
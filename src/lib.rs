@@ -1,5 +1,8 @@
+pub mod audit;
 pub mod config;
+pub mod crd;
 pub mod error;
 pub mod http;
 #[cfg(target_os = "linux")]
 pub mod netlink;
+pub mod telemetry;
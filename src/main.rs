@@ -3,30 +3,35 @@ use std::sync::Arc;
 use anyhow::Error;
 use clap::Parser;
 use homelab_aws_creds::config::Cli;
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
+use homelab_aws_creds::telemetry::Telemetry;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "homelab_aws_creds=info,tower_http=info".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
     let cli = Cli::parse();
-    match cli.command {
+    let telemetry = Telemetry::init(&cli.telemetry_config)?;
+
+    let result = match cli.command {
         homelab_aws_creds::config::Commands::Agent(agent_config) => {
             homelab_aws_creds::http::serve_agent(Arc::new(agent_config)).await
         }
         homelab_aws_creds::config::Commands::Webhook(webhook_config) => {
             homelab_aws_creds::http::serve_webhook(Arc::new(webhook_config)).await
         }
+        homelab_aws_creds::config::Commands::Proxy(proxy_config) => {
+            homelab_aws_creds::http::serve_proxy(Arc::new(proxy_config)).await
+        }
+        homelab_aws_creds::config::Commands::Crd => homelab_aws_creds::crd::print_crds(),
+        homelab_aws_creds::config::Commands::ValidateMappings(validate_config) => {
+            homelab_aws_creds::http::validate_mappings(&validate_config).await
+        }
+        homelab_aws_creds::config::Commands::MappingsSchema => {
+            homelab_aws_creds::http::print_mappings_schema()
+        }
         #[cfg(target_os = "linux")]
         homelab_aws_creds::config::Commands::Netlink => {
             homelab_aws_creds::netlink::init_local_link().await
         }
-    }
+    };
+    telemetry.shutdown();
+    result
 }